@@ -0,0 +1,237 @@
+use headless_chrome::{Browser, LaunchOptionsBuilder, protocol::{Event, page::{ScreenshotFormat, Viewport}}, Tab};
+use std::{mem, process::Command, sync::{Arc, Mutex}, time::{Duration, Instant}};
+use puzzle_card::PuzzleCard;
+use crate::{config::{Config, exit_with_error}, devtools::{SetDeviceMetricsOverride, SetDefaultBackgroundColorOverride, Rgba, AddScriptToEvaluateOnNewDocument, ClosePage, page_error}, report::Timings, watchdog::Watchdog};
+
+// The card page sets this attribute once its fonts, images and videos have rendered.
+const READY_SELECTOR: &str = "[data-ready-to-capture]";
+
+// The felt background is set on the ready element. Removing it leaves only the
+// default background, which is made transparent for --transparent.
+const REMOVE_BACKGROUND: &str = "document.querySelector('[data-ready-to-capture]').style.background = 'none'";
+
+// Collects the page's console.error calls, with React's %s placeholders filled
+// in, for TAKE_CONSOLE_ERRORS to read before the screenshot.
+const RECORD_CONSOLE_ERRORS: &str = r#"
+  window.consoleErrors = [];
+  const consoleError = console.error;
+
+  console.error = (...args) => {
+    const [first, ...rest] = args.map(a => a instanceof Error ? a.message : String(a));
+    window.consoleErrors.push([first.replace(/%[sdifoO]/g, m => rest.length ? rest.shift() : m), ...rest].join(" "));
+    consoleError.apply(console, args);
+  };
+"#;
+
+const TAKE_CONSOLE_ERRORS: &str = "JSON.stringify((window.consoleErrors || []).splice(0))";
+
+// Stop collecting a page's errors after this many so a broken page can't use up memory.
+const MAX_PAGE_ERRORS: usize = 20;
+
+// Loads card pages in tabs and screenshots them. Chrome is the real thing and
+// the tests use a fake so that the orchestration in run.rs works offline.
+pub trait CardCapturer: Send + Sync {
+    type Tab;
+
+    fn index(&self) -> usize;
+    fn generation(&self) -> usize; // Increases each time the capturer restarts.
+    fn kill(&self, generation: usize); // So the watchdog can unblock its tabs. Ignored if it's since restarted.
+
+    fn new_tab(&self) -> Result<(Self::Tab, usize), String>;
+    fn close_tab(&self, tab: &Self::Tab) -> Result<(), String>;
+    fn restart(&self, generation: usize) -> Result<(), String>;
+
+    // The errors the page logged before the tab's last card failed.
+    fn take_page_errors(&self, tab: &Self::Tab) -> Vec<String>;
+
+    fn capture_card(&self, tab: &Self::Tab, watchdog: &Watchdog, slot: usize, token_id: u128) -> Result<Screenshot, String>;
+}
+
+// A Chrome instance whose tabs each load and capture cards independently. Tabs
+// that get stuck are replaced and Chrome is restarted if that doesn't work.
+pub struct ChromeInstance {
+    index: usize,
+    config: Arc<Config>,
+    browser: Mutex<(Browser, usize)>, // The generation increases each time Chrome restarts.
+    process_id: Mutex<(Option<u32>, usize)>, // Apart from the browser because a stuck call might hold its lock.
+}
+
+// A tab and the errors its page has logged since the current card started loading.
+pub struct ChromeTab {
+    tab: Arc<Tab>,
+    page_errors: Arc<Mutex<Vec<String>>>,
+}
+
+pub struct Screenshot {
+    pub token_id: u128,
+    pub card: PuzzleCard,
+    pub clip: Viewport,
+    pub png_bytes: Vec<u8>,
+    pub timings: Timings,
+    pub page_errors: Vec<String>,
+}
+
+impl ChromeInstance {
+    // Exits if Chrome can't load the site when the run starts.
+    pub fn launch(index: usize, config: Arc<Config>) -> Self {
+        let browser = launch_browser(&config).unwrap_or_else(|e| exit_with_error(&e));
+        let process_id = Mutex::new((browser.get_process_id(), 0));
+
+        ChromeInstance { index, config, browser: Mutex::new((browser, 0)), process_id }
+    }
+}
+
+impl CardCapturer for ChromeInstance {
+    type Tab = ChromeTab;
+
+    fn index(&self) -> usize {
+        self.index
+    }
+
+    fn generation(&self) -> usize {
+        self.browser.lock().unwrap().1
+    }
+
+    fn kill(&self, generation: usize) {
+        let (process_id, current_generation) = *self.process_id.lock().unwrap();
+        if current_generation != generation { return; }
+
+        if let Some(pid) = process_id { let _ = Command::new("kill").args(["-9", &pid.to_string()]).status(); }
+    }
+
+    fn new_tab(&self) -> Result<(ChromeTab, usize), String> {
+        let browser = self.browser.lock().unwrap();
+        let tab = browser.0.new_tab().map_err(|e| format!("Could not open a tab: {}", e))?;
+
+        // Emulate the pixel density so screenshots don't depend on the host's display.
+        let (width, height) = self.config.viewport_size();
+        let device_scale_factor = self.config.device_scale_factor as f64;
+
+        tab.set_default_timeout(self.config.navigation_timeout());
+        tab.call_method(SetDeviceMetricsOverride { width, height, device_scale_factor, mobile: false })
+            .map_err(|e| format!("Could not set the viewport size: {}", e))?;
+
+        if self.config.transparent {
+            tab.call_method(SetDefaultBackgroundColorOverride { color: Rgba { r: 0, g: 0, b: 0, a: 0. } })
+                .map_err(|e| format!("Could not make the background transparent: {}", e))?;
+        }
+
+        let page_errors = Arc::new(Mutex::new(vec![]));
+        let errors = Arc::clone(&page_errors);
+
+        tab.enable_log().and_then(|tab| tab.enable_runtime()).map_err(|e| format!("Could not listen for page errors: {}", e))?;
+        tab.call_method(AddScriptToEvaluateOnNewDocument { source: RECORD_CONSOLE_ERRORS.to_string() }).map_err(|e| format!("Could not listen for page errors: {}", e))?;
+
+        tab.add_event_listener(Arc::new(move |event: &Event| {
+            let mut errors = errors.lock().unwrap();
+            if errors.len() >= MAX_PAGE_ERRORS { return; }
+
+            if let Some(error) = page_error(event) { errors.push(error); }
+        })).map_err(|e| format!("Could not listen for page errors: {}", e))?;
+
+        Ok((ChromeTab { tab, page_errors }, browser.1))
+    }
+
+    fn close_tab(&self, tab: &ChromeTab) -> Result<(), String> {
+        tab.tab.call_method(ClosePage {}).map(|_| ()).map_err(|e| format!("Could not close the tab: {}", e))
+    }
+
+    // Other tabs may try to restart Chrome for the same failure so only the first
+    // one does. The old browser is kept if it fails so that the next attempt retries.
+    fn restart(&self, generation: usize) -> Result<(), String> {
+        let mut browser = self.browser.lock().unwrap();
+        if browser.1 != generation { return Ok(()); }
+
+        *browser = (launch_browser(&self.config)?, generation + 1);
+        *self.process_id.lock().unwrap() = (browser.0.get_process_id(), browser.1);
+
+        Ok(())
+    }
+
+    fn take_page_errors(&self, tab: &ChromeTab) -> Vec<String> {
+        mem::take(&mut *tab.page_errors.lock().unwrap())
+    }
+
+    fn capture_card(&self, tab: &ChromeTab, watchdog: &Watchdog, slot: usize, token_id: u128) -> Result<Screenshot, String> {
+        capture_card(tab, &self.config, watchdog, slot, token_id)
+    }
+}
+
+fn launch_browser(config: &Config) -> Result<Browser, String> {
+    let (width, height) = config.viewport_size();
+
+    let options = LaunchOptionsBuilder::default()
+        .headless(true)
+        .window_size(Some((width, height)))
+        .idle_browser_timeout(config.stall_timeout())
+        .build().unwrap();
+
+    let browser = Browser::new(options).map_err(|e| format!("Could not launch Chrome: {}", e))?;
+    let tab = browser.wait_for_initial_tab().map_err(|e| format!("Chrome didn't open a tab: {}", e))?;
+
+    tab.set_default_timeout(config.navigation_timeout());
+    check_if_server_running(&tab, config)?;
+
+    Ok(browser)
+}
+
+fn check_if_server_running(tab: &Arc<Tab>, config: &Config) -> Result<(), String> {
+    let mut success = false;
+
+    // This also seems to fix the first captured image sometimes not having its
+    // text scaled correctly so it's worth doing all 3 iterations.
+    for i in 0..3 {
+        if tab.navigate_to(&config.card_url(i)).is_err() { continue; }
+        if tab.wait_until_navigated().is_err() { continue; }
+
+        success = true;
+    }
+
+    if !success && config.external_server {
+        Err("No server running. Start it with ./bin/serve_website_static".to_string())
+    } else if !success {
+        Err(format!("Could not load {} from the built-in server", config.card_url(0)))
+    } else {
+        Ok(())
+    }
+}
+
+// Each step fails if it errors or overruns its deadline so that the tab is replaced.
+fn capture_card(chrome_tab: &ChromeTab, config: &Config, watchdog: &Watchdog, slot: usize, token_id: u128) -> Result<Screenshot, String> {
+    let card = PuzzleCard::from_token_id(token_id).unwrap();
+    let ready_timeout = config.ready_timeout(card.card_type);
+    let started = Instant::now();
+
+    let tab = &chrome_tab.tab;
+    chrome_tab.page_errors.lock().unwrap().clear();
+
+    watchdog.run_step(slot, token_id, "Navigation", config.navigation_timeout(), || tab.navigate_to(&config.card_url(token_id)))?;
+    watchdog.run_step(slot, token_id, "Navigation", config.navigation_timeout(), || tab.wait_until_navigated())?;
+    let navigated = Instant::now();
+
+    watchdog.run_step(slot, token_id, "Waiting for the page to be ready", ready_timeout, || tab.wait_for_element_with_custom_timeout(READY_SELECTOR, ready_timeout))?;
+    let ready = Instant::now();
+
+    if config.transparent {
+        watchdog.run_step(slot, token_id, "Removing the background", config.navigation_timeout(), || tab.evaluate(REMOVE_BACKGROUND, false))?;
+    }
+
+    let console_errors = watchdog.run_step(slot, token_id, "Reading console errors", config.navigation_timeout(), || tab.evaluate(TAKE_CONSOLE_ERRORS, false))?;
+    let console_errors = console_errors.value.and_then(|v| serde_json::from_str::<Vec<String>>(v.as_str()?).ok()).unwrap_or_default();
+
+    let clip = watchdog.run_step(slot, token_id, "Locating the card", config.navigation_timeout(), || tab.find_element(&config.card_selector).and_then(|e| e.get_box_model()))?;
+    let clip = rounded_viewport(clip.border_viewport());
+
+    let png_bytes = watchdog.run_step(slot, token_id, "Screenshot", config.screenshot_timeout(), || tab.capture_screenshot(ScreenshotFormat::PNG, Some(clip.clone()), true))?;
+
+    let timings = Timings { navigation: navigated - started, ready: ready - navigated, screenshot: ready.elapsed(), encode: Duration::ZERO };
+    let mut page_errors = mem::take(&mut *chrome_tab.page_errors.lock().unwrap());
+    page_errors.extend(console_errors.into_iter().take(MAX_PAGE_ERRORS.saturating_sub(page_errors.len())));
+
+    Ok(Screenshot { token_id, card, clip, png_bytes, timings, page_errors })
+}
+
+// Round the clip to whole CSS pixels so that the screenshot's size is exact.
+fn rounded_viewport(viewport: Viewport) -> Viewport {
+    Viewport { x: viewport.x.round(), y: viewport.y.round(), width: viewport.width.round(), height: viewport.height.round(), scale: 1. }
+}
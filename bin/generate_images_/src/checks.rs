@@ -0,0 +1,285 @@
+use std::{collections::HashMap, sync::Mutex};
+use clap::ValueEnum;
+use image::{DynamicImage, GenericImageView, GrayImage};
+use serde::Deserialize;
+use puzzle_card::{CardType, PuzzleCard, Tier};
+use crate::config::Config;
+
+// Screenshots can be blank, half-loaded or badly scaled without Chrome reporting
+// an error so these checks catch them before they're written to disk.
+
+// Screenshots are clipped to the card so the page background only shows in
+// its rounded corners, which are sized by CardViewer/styles.module.scss.
+const CORNER_FRACTION: u32 = 100;
+
+const MAX_UNIFORM_FRACTION: f32 = 0.9;
+const MIN_BORDER_DIFFERENCE: f32 = 12.;
+const MIN_TEXT_SHARPNESS: f32 = 40.;
+const MAX_CORNER_ALPHA: f32 = 32.;
+const MIN_EDGE_ALPHA: f32 = 224.;
+
+const HISTOGRAM_BINS: usize = 16;
+const MIN_SAMPLES_FOR_NORM: u32 = 20;
+const MAX_HISTOGRAM_DISTANCE: f32 = 0.8; // Out of a maximum of 2.
+
+type Histogram = [f32; HISTOGRAM_BINS]; // The fraction of pixels in each band of luminance.
+
+// Each check can be turned off with disabled_checks in case it rejects valid cards.
+#[derive(Deserialize, ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Check {
+    Blank,
+    Border,
+    Sharpness,
+    Brightness,
+}
+
+// A running average of the luminance histograms of accepted captures per card
+// type and tier because the tiers are styled differently.
+#[derive(Default)]
+pub struct LuminanceNorms {
+    norms: Mutex<HashMap<(CardType, Tier), (Histogram, u32)>>,
+}
+
+pub fn check_screenshot(image: &DynamicImage, card: &PuzzleCard, norms: &LuminanceNorms, config: &Config) -> Result<(), String> {
+    let gray = image.to_luma8();
+    let enabled = |check| !config.disabled_checks.contains(&check);
+
+    if enabled(Check::Blank) { check_not_uniform(&gray)?; }
+
+    if enabled(Check::Border) {
+        if config.transparent { check_transparent_corners(image)?; } else { check_card_border(image)?; }
+    }
+
+    if enabled(Check::Sharpness) { check_text_sharpness(&gray)?; }
+    if enabled(Check::Brightness) { norms.check_and_update(card, luminance_histogram(&gray))?; }
+
+    Ok(())
+}
+
+fn check_not_uniform(gray: &GrayImage) -> Result<(), String> {
+    let mut counts = [0_u32; 256];
+    gray.pixels().for_each(|p| counts[p[0] as usize] += 1);
+
+    // Allow for compression noise by counting neighbouring luminance values.
+    let most_common = counts.windows(3).map(|w| w.iter().sum::<u32>()).max().unwrap_or(0);
+    let fraction = most_common as f32 / (gray.width() * gray.height()) as f32;
+
+    if fraction > MAX_UNIFORM_FRACTION {
+        return Err(format!("{:.0}% of the screenshot is a single color", fraction * 100.));
+    }
+
+    Ok(())
+}
+
+// Compares strips just inside each edge of the card with the background in its corners.
+fn check_card_border(image: &DynamicImage) -> Result<(), String> {
+    let background = corners(image.dimensions()).map(|(cx, cy, cw, ch)| mean_color(image, cx, cy, cw, ch))
+        .iter().fold([0.; 3], |sum, color| [0, 1, 2].map(|i| sum[i] + color[i] / 4.));
+
+    for (side, (sx, sy, sw, sh)) in edge_strips(image.dimensions()) {
+        let strip = mean_color(image, sx, sy, sw, sh);
+        let difference = strip.iter().zip(background).map(|(a, b)| (a - b).abs()).sum::<f32>() / 3.;
+
+        if difference < MIN_BORDER_DIFFERENCE {
+            return Err(format!("The {} edge of the card is missing", side));
+        }
+    }
+
+    Ok(())
+}
+
+// With --transparent there's no background so the corners should be see-through
+// and the card's edges opaque.
+fn check_transparent_corners(image: &DynamicImage) -> Result<(), String> {
+    for (cx, cy, cw, ch) in corners(image.dimensions()) {
+        if mean_alpha(image, cx, cy, cw, ch) > MAX_CORNER_ALPHA {
+            return Err("The corners of the card aren't transparent".to_string());
+        }
+    }
+
+    for (side, (sx, sy, sw, sh)) in edge_strips(image.dimensions()) {
+        if mean_alpha(image, sx, sy, sw, sh) < MIN_EDGE_ALPHA {
+            return Err(format!("The {} edge of the card is transparent", side));
+        }
+    }
+
+    Ok(())
+}
+
+type Rect = (u32, u32, u32, u32); // x, y, width, height
+
+// Squares in each corner that are outside the card's rounded border.
+fn corners((width, height): (u32, u32)) -> [Rect; 4] {
+    let corner = (width / CORNER_FRACTION).max(1);
+    [(0, 0), (width - corner, 0), (0, height - corner), (width - corner, height - corner)].map(|(x, y)| (x, y, corner, corner))
+}
+
+// Strips just inside each edge of the card.
+fn edge_strips((width, height): (u32, u32)) -> [(&'static str, Rect); 4] {
+    let thickness = (width / 50).max(1);
+
+    [
+        ("left", (thickness, height / 4, thickness, height / 2)),
+        ("right", (width - 2 * thickness, height / 4, thickness, height / 2)),
+        ("top", (width / 4, thickness, width / 2, thickness)),
+        ("bottom", (width / 4, height - 2 * thickness, width / 2, thickness)),
+    ]
+}
+
+// The variance of the Laplacian is low when the text at the top of the card is blurry.
+fn check_text_sharpness(gray: &GrayImage) -> Result<(), String> {
+    let (width, height) = gray.dimensions();
+    let (top, bottom) = (height * 3 / 100, height * 12 / 100);
+
+    let mut values = vec![];
+
+    for py in top.max(1)..bottom.min(height - 1) {
+        for px in (width / 10)..(width * 9 / 10).min(width - 1) {
+            let at = |dx: i32, dy: i32| gray.get_pixel((px as i32 + dx) as u32, (py as i32 + dy) as u32)[0] as f32;
+            values.push(at(-1, 0) + at(1, 0) + at(0, -1) + at(0, 1) - 4. * at(0, 0));
+        }
+    }
+
+    if values.is_empty() { return Ok(()); }
+
+    let mean = values.iter().sum::<f32>() / values.len() as f32;
+    let variance = values.iter().map(|v| (v - mean).powi(2)).sum::<f32>() / values.len() as f32;
+
+    if variance < MIN_TEXT_SHARPNESS {
+        return Err(format!("The text at the top of the card is blurry (sharpness {:.0})", variance));
+    }
+
+    Ok(())
+}
+
+impl LuminanceNorms {
+    fn check_and_update(&self, card: &PuzzleCard, histogram: Histogram) -> Result<(), String> {
+        let mut norms = self.norms.lock().unwrap();
+        let (sum, count) = norms.entry((card.card_type, card.tier)).or_insert(([0.; HISTOGRAM_BINS], 0));
+
+        if *count >= MIN_SAMPLES_FOR_NORM {
+            let distance = sum.iter().zip(histogram).map(|(s, h)| (s / *count as f32 - h).abs()).sum::<f32>();
+
+            if distance > MAX_HISTOGRAM_DISTANCE {
+                return Err(format!("The brightness is unusual for a {} {} card (distance {:.2})", card.tier, card.card_type, distance));
+            }
+        }
+
+        sum.iter_mut().zip(histogram).for_each(|(s, h)| *s += h);
+        *count += 1;
+
+        Ok(())
+    }
+}
+
+fn luminance_histogram(gray: &GrayImage) -> Histogram {
+    let mut histogram = [0.; HISTOGRAM_BINS];
+    gray.pixels().for_each(|p| histogram[p[0] as usize * HISTOGRAM_BINS / 256] += 1.);
+
+    let num_pixels = (gray.width() * gray.height()) as f32;
+    histogram.map(|h| h / num_pixels)
+}
+
+fn mean_color(image: &DynamicImage, x: u32, y: u32, width: u32, height: u32) -> [f32; 3] {
+    let mut sum = [0.; 3];
+    let mut count = 0_f32;
+
+    for py in y..(y + height).min(image.height()) {
+        for px in x..(x + width).min(image.width()) {
+            let pixel = image.get_pixel(px, py);
+            (0..3).for_each(|i| sum[i] += pixel[i] as f32);
+            count += 1.;
+        }
+    }
+
+    sum.map(|s| s / count.max(1.))
+}
+
+fn mean_alpha(image: &DynamicImage, x: u32, y: u32, width: u32, height: u32) -> f32 {
+    let mut sum = 0.;
+    let mut count = 0_f32;
+
+    for py in y..(y + height).min(image.height()) {
+        for px in x..(x + width).min(image.width()) {
+            sum += image.get_pixel(px, py)[3] as f32;
+            count += 1.;
+        }
+    }
+
+    sum / count.max(1.)
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgba, RgbaImage};
+    use super::*;
+
+    const BACKGROUND: Rgba<u8> = Rgba([20, 60, 20, 255]);
+
+    // Like a screenshot of a card: the background in its corners, text at the
+    // top and a slight gradient at the given brightness elsewhere.
+    fn screenshot(brightness: u8, background: Rgba<u8>, sharp_text: bool) -> DynamicImage {
+        let (width, height) = (200, 280);
+        let radius = width / 30;
+
+        DynamicImage::ImageRgba8(RgbaImage::from_fn(width, height, |x, y| {
+            let in_corner = (x < radius || x >= width - radius) && (y < radius || y >= height - radius);
+            let in_text = y >= height * 3 / 100 && y < height * 12 / 100;
+
+            if in_corner { background }
+            else if in_text && sharp_text { if (x + y) % 2 == 0 { Rgba([0, 0, 0, 255]) } else { Rgba([255, 255, 255, 255]) } }
+            else { Rgba([brightness, brightness + (y * 20 / height) as u8, brightness, 255]) }
+        }))
+    }
+
+    fn cards() -> (PuzzleCard, PuzzleCard) {
+        let first = PuzzleCard::all().next().unwrap();
+        let other_tier = PuzzleCard::all().find(|card| card.card_type == first.card_type && card.tier != first.tier).unwrap();
+
+        (first, other_tier)
+    }
+
+    #[test]
+    fn accepts_a_card_with_a_border_and_sharp_text() {
+        let (card, _) = cards();
+        assert_eq!(check_screenshot(&screenshot(200, BACKGROUND, true), &card, &LuminanceNorms::default(), &Config::default()), Ok(()));
+    }
+
+    #[test]
+    fn rejects_a_card_whose_edges_match_the_background() {
+        let image = screenshot(200, Rgba([200, 210, 200, 255]), true);
+        assert_eq!(check_card_border(&image), Err("The left edge of the card is missing".to_string()));
+    }
+
+    #[test]
+    fn rejects_blurry_text() {
+        let gray = screenshot(200, BACKGROUND, false).to_luma8();
+        assert!(check_text_sharpness(&gray).unwrap_err().starts_with("The text at the top of the card is blurry"));
+    }
+
+    #[test]
+    fn rejects_unusual_brightness_for_the_type_and_tier() {
+        let (card, other_tier) = cards();
+        let (norms, config) = (LuminanceNorms::default(), Config::default());
+
+        for _ in 0..MIN_SAMPLES_FOR_NORM {
+            check_screenshot(&screenshot(200, BACKGROUND, true), &card, &norms, &config).unwrap();
+        }
+
+        let dark = screenshot(90, BACKGROUND, true);
+
+        assert!(check_screenshot(&dark, &card, &norms, &config).unwrap_err().starts_with("The brightness is unusual"));
+        assert_eq!(check_screenshot(&dark, &other_tier, &norms, &config), Ok(()));
+    }
+
+    #[test]
+    fn skips_disabled_checks() {
+        let (card, _) = cards();
+        let blurry = screenshot(200, BACKGROUND, false);
+        let config = Config { disabled_checks: vec![Check::Sharpness], ..Config::default() };
+
+        assert!(check_screenshot(&blurry, &card, &LuminanceNorms::default(), &Config::default()).is_err());
+        assert_eq!(check_screenshot(&blurry, &card, &LuminanceNorms::default(), &config), Ok(()));
+    }
+}
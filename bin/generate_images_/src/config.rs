@@ -0,0 +1,431 @@
+use std::{fs, path::PathBuf, collections::BTreeMap, time::Duration};
+use clap::{Parser, Subcommand};
+use serde::Deserialize;
+use puzzle_card::{Filter, CardType};
+use crate::{checks::Check, shard::Shard};
+
+// Settings are read from the defaults below, then the --config file, then the
+// command-line flags. Paths are relative to the root of the repository because
+// that is where ./bin/generate_images runs from. Flags that turn a setting on
+// can turn it off with e.g. --transparent=false.
+
+#[derive(Parser, Debug)]
+#[command(about = "Visits every card page and takes a screenshot.")]
+pub struct Args {
+    /// A TOML file that sets any of the options below, e.g. num_threads = 8
+    #[arg(long)]
+    pub config: Option<PathBuf>,
+
+    /// Capture cards that match e.g. 'tier=Master,type=Artwork' even if their images exist
+    #[arg(long)]
+    pub only: Option<Filter>,
+
+    /// Print what would be captured and quarantined without doing either
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// Capture only this machine's share of the cards, e.g. '2/4', into its own directory and manifest
+    #[arg(long)]
+    pub shard: Option<Shard>,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// Viewport width in device pixels (default 1050)
+    #[arg(long)]
+    pub capture_width: Option<u32>,
+
+    /// Viewport height in device pixels (default 1050)
+    #[arg(long)]
+    pub capture_height: Option<u32>,
+
+    /// The card is resized to fit within this width in pixels (default 350)
+    #[arg(long)]
+    pub output_width: Option<u32>,
+
+    /// The card is resized to fit within this height in pixels (default 350)
+    #[arg(long)]
+    pub output_height: Option<u32>,
+
+    /// The element on the card page that is captured (default [data-capture-target])
+    #[arg(long)]
+    pub card_selector: Option<String>,
+
+    /// Where images are written (default public_s3/card_images)
+    #[arg(long)]
+    pub output_directory: Option<String>,
+
+    /// Capture cut-out cards with transparent corners as PNGs into the transparent_directory
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    pub transparent: Option<bool>,
+
+    /// Where --transparent images are written (default card_images_transparent)
+    #[arg(long)]
+    pub transparent_directory: Option<String>,
+
+    /// Where images with no corresponding card are moved to, in a dated subdirectory (default card_images_quarantine)
+    #[arg(long)]
+    pub quarantine_directory: Option<String>,
+
+    /// Refuse to run if more than this percentage of images would be quarantined (default 5)
+    #[arg(long)]
+    pub max_surplus_percent: Option<f64>,
+
+    /// Records what each image was captured from so changed ones are re-captured (default card_images_manifest.tsv)
+    #[arg(long)]
+    pub manifest_path: Option<String>,
+
+    /// A JSON Lines file with each card's outcome, retries and timings (default card_images_report.jsonl)
+    #[arg(long)]
+    pub report_path: Option<String>,
+
+    /// Reject screenshots of pages that logged errors, e.g. an image that 404'd, so they're retried
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    pub fail_on_page_errors: Option<bool>,
+
+    /// Checks of the screenshots to skip, e.g. 'brightness,sharpness', in case they reject valid cards
+    #[arg(long, value_delimiter = ',')]
+    pub disabled_checks: Option<Vec<Check>>,
+
+    /// Decode every existing image and re-capture any that are corrupt or the wrong size
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    pub verify_existing: Option<bool>,
+
+    /// Checked against the cards if it exists (default public_s3/metadata_api)
+    #[arg(long)]
+    pub metadata_directory: Option<String>,
+
+    /// Set to 0 to output lossless PNGs instead of JPEGs (default 75)
+    #[arg(long)]
+    pub jpeg_quality: Option<u8>,
+
+    /// Device pixels per CSS pixel, so the page is laid out at the capture size divided by this (default 2)
+    #[arg(long)]
+    pub device_scale_factor: Option<u32>,
+
+    /// How many Chrome instances to run at once (default 4)
+    #[arg(long)]
+    pub num_threads: Option<u32>,
+
+    /// How many tabs each Chrome instance loads cards in at once (default 4)
+    #[arg(long)]
+    pub tabs_per_instance: Option<u32>,
+
+    /// How many threads resize and encode screenshots, or 0 for one per core (default 0)
+    #[arg(long)]
+    pub encode_threads: Option<usize>,
+
+    /// How many screenshots can wait to be encoded before tabs wait for the encoders (default 32)
+    #[arg(long)]
+    pub encode_queue_size: Option<usize>,
+
+    /// The card page to capture where {id} is replaced with the token ID and {origin} with the server's address
+    #[arg(long)]
+    pub url_template: Option<String>,
+
+    /// The exported website that is served while capturing (default .gh-pages)
+    #[arg(long)]
+    pub site_directory: Option<String>,
+
+    /// Use the server from ./bin/serve_website_static on port 5000 instead of serving site_directory
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    pub external_server: Option<bool>,
+
+    /// Seconds to wait for the card page to be ready to capture (default 10)
+    #[arg(long)]
+    pub ready_timeout: Option<u64>,
+
+    /// Seconds to wait for the card page to load (default 20)
+    #[arg(long)]
+    pub navigation_timeout: Option<u64>,
+
+    /// Seconds to wait for Chrome to take a screenshot (default 15)
+    #[arg(long)]
+    pub screenshot_timeout: Option<u64>,
+
+    /// Seconds a Chrome instance can make no progress before it's killed and restarted (default 90)
+    #[arg(long)]
+    pub stall_timeout: Option<u64>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Move every shard's images into the output directory and combine their manifests
+    Merge {
+        /// How many shards the cards were split into
+        #[arg(long)]
+        shards: u32,
+    },
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub capture_width: u32,
+    pub capture_height: u32,
+    pub output_width: u32,
+    pub output_height: u32,
+    pub card_selector: String,
+    pub output_directory: String,
+    pub transparent: bool,
+    pub transparent_directory: String,
+    pub quarantine_directory: String,
+    pub max_surplus_percent: f64,
+    pub manifest_path: String,
+    pub report_path: String,
+    pub verify_existing: bool,
+    pub fail_on_page_errors: bool,
+    pub disabled_checks: Vec<Check>,
+    pub metadata_directory: String,
+    pub jpeg_quality: u8, // Or output a lossless PNG if 0.
+    pub device_scale_factor: u32,
+    pub num_threads: u32,
+    pub tabs_per_instance: u32,
+    pub encode_threads: usize,
+    pub encode_queue_size: usize,
+    pub url_template: String,
+    pub site_directory: String,
+    pub external_server: bool,
+    pub ready_timeout: u64,
+    pub ready_timeout_per_type: BTreeMap<String, u64>, // e.g. Cloak = 20
+    pub navigation_timeout: u64,
+    pub screenshot_timeout: u64,
+    pub stall_timeout: u64,
+    pub renditions: Vec<Rendition>, // Only set in the --config file.
+
+    #[serde(skip)]
+    pub rendition_format: Option<RenditionFormat>, // Only set by rendition_configs.
+
+    #[serde(skip)]
+    pub origin: String, // Replaced with the built-in server's address once it starts.
+}
+
+// An extra size or format written from the same screenshot, e.g. for srcset:
+//
+//   [[renditions]]
+//   directory = "public_s3/card_images_700"
+//   width = 700
+//   height = 700
+//   format = "avif"
+//   quality = 60
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Rendition {
+    pub directory: String,
+    pub width: u32,
+    pub height: u32,
+    pub format: RenditionFormat,
+    #[serde(default = "default_quality")]
+    pub quality: u8, // From 1 to 100. Not used for PNGs.
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RenditionFormat {
+    Jpeg,
+    Png,
+    Webp,
+    Avif,
+}
+
+fn default_quality() -> u8 { 75 }
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            capture_width: 1050,
+            capture_height: 1050,
+            output_width: 350,
+            output_height: 350,
+            card_selector: "[data-capture-target]".to_string(),
+            output_directory: "public_s3/card_images".to_string(),
+            transparent: false,
+            transparent_directory: "card_images_transparent".to_string(),
+            quarantine_directory: "card_images_quarantine".to_string(),
+            max_surplus_percent: 5.,
+            manifest_path: "card_images_manifest.tsv".to_string(),
+            report_path: "card_images_report.jsonl".to_string(),
+            verify_existing: false,
+            fail_on_page_errors: false,
+            disabled_checks: vec![],
+            metadata_directory: "public_s3/metadata_api".to_string(),
+            jpeg_quality: 75,
+            device_scale_factor: 2,
+            num_threads: 4,
+            tabs_per_instance: 4,
+            encode_threads: 0,
+            encode_queue_size: 32,
+            url_template: "{origin}/card?tokenID={id}&referrer=generate_images".to_string(),
+            site_directory: ".gh-pages".to_string(),
+            external_server: false,
+            ready_timeout: 10,
+            ready_timeout_per_type: BTreeMap::from([("Cloak".to_string(), 20)]),
+            navigation_timeout: 20,
+            screenshot_timeout: 15,
+            stall_timeout: 90,
+            renditions: vec![],
+            rendition_format: None,
+            origin: "http://localhost:5000".to_string(),
+        }
+    }
+}
+
+impl Config {
+    pub fn load(args: &Args) -> Self {
+        let mut config = match &args.config {
+            Some(path) => {
+                let content = fs::read_to_string(path).unwrap_or_else(|e| exit_with_error(&format!("Could not read {}: {}", path.display(), e)));
+                toml::from_str(&content).unwrap_or_else(|e| exit_with_error(&format!("Could not parse {}: {}", path.display(), e)))
+            },
+            None => Config::default(),
+        };
+
+        if let Some(v) = args.capture_width { config.capture_width = v; }
+        if let Some(v) = args.capture_height { config.capture_height = v; }
+        if let Some(v) = args.output_width { config.output_width = v; }
+        if let Some(v) = args.output_height { config.output_height = v; }
+        if let Some(v) = &args.card_selector { config.card_selector = v.clone(); }
+        if let Some(v) = &args.output_directory { config.output_directory = v.clone(); }
+        if let Some(v) = args.transparent { config.transparent = v; }
+        if let Some(v) = &args.transparent_directory { config.transparent_directory = v.clone(); }
+        if let Some(v) = &args.quarantine_directory { config.quarantine_directory = v.clone(); }
+        if let Some(v) = args.max_surplus_percent { config.max_surplus_percent = v; }
+        if let Some(v) = &args.manifest_path { config.manifest_path = v.clone(); }
+        if let Some(v) = &args.report_path { config.report_path = v.clone(); }
+        if let Some(v) = args.verify_existing { config.verify_existing = v; }
+        if let Some(v) = args.fail_on_page_errors { config.fail_on_page_errors = v; }
+        if let Some(v) = &args.disabled_checks { config.disabled_checks = v.clone(); }
+        if let Some(v) = &args.metadata_directory { config.metadata_directory = v.clone(); }
+        if let Some(v) = args.jpeg_quality { config.jpeg_quality = v; }
+        if let Some(v) = args.device_scale_factor { config.device_scale_factor = v; }
+        if let Some(v) = args.num_threads { config.num_threads = v; }
+        if let Some(v) = args.tabs_per_instance { config.tabs_per_instance = v; }
+        if let Some(v) = args.encode_threads { config.encode_threads = v; }
+        if let Some(v) = args.encode_queue_size { config.encode_queue_size = v; }
+        if let Some(v) = &args.url_template { config.url_template = v.clone(); }
+        if let Some(v) = &args.site_directory { config.site_directory = v.clone(); }
+        if let Some(v) = args.external_server { config.external_server = v; }
+        if let Some(v) = args.ready_timeout { config.ready_timeout = v; }
+        if let Some(v) = args.navigation_timeout { config.navigation_timeout = v; }
+        if let Some(v) = args.screenshot_timeout { config.screenshot_timeout = v; }
+        if let Some(v) = args.stall_timeout { config.stall_timeout = v; }
+
+        if !config.url_template.contains("{id}") { exit_with_error("The url_template must contain {id}"); }
+        if config.num_threads == 0 { exit_with_error("The num_threads must be at least 1"); }
+        if config.tabs_per_instance == 0 { exit_with_error("The tabs_per_instance must be at least 1"); }
+        if config.device_scale_factor == 0 { exit_with_error("The device_scale_factor must be at least 1"); }
+
+        if config.capture_width % config.device_scale_factor != 0 || config.capture_height % config.device_scale_factor != 0 {
+            exit_with_error("The capture_width and capture_height must be multiples of the device_scale_factor");
+        }
+
+        // Otherwise the watchdog kills Chrome while it's still within a step's deadline.
+        let longest_ready_timeout = config.ready_timeout_per_type.values().copied().chain([config.ready_timeout]).max().unwrap();
+        let longest_step = config.navigation_timeout.max(longest_ready_timeout).max(config.screenshot_timeout);
+
+        if config.stall_timeout <= longest_step { exit_with_error(&format!("The stall_timeout must be longer than the longest step ({}s)", longest_step)); }
+
+        for rendition in &config.renditions {
+            if rendition.width == 0 || rendition.height == 0 { exit_with_error(&format!("The rendition in {} must have a width and height", rendition.directory)); }
+
+            if rendition.format != RenditionFormat::Png && !(1..=100).contains(&rendition.quality) {
+                exit_with_error(&format!("The quality of the rendition in {} must be from 1 to 100", rendition.directory));
+            }
+
+            if rendition.width > config.capture_width || rendition.height > config.capture_height {
+                exit_with_error(&format!("The {}x{} rendition in {} is larger than the capture size so it would be upscaled", rendition.width, rendition.height, rendition.directory));
+            }
+        }
+
+        for type_name in config.ready_timeout_per_type.keys() {
+            if CardType::from_name(type_name).is_none() { exit_with_error(&format!("'{}' in ready_timeout_per_type is not a card type", type_name)); }
+        }
+
+        // Cut-out cards are kept apart from the JPEGs so each has its own manifest and report.
+        if config.transparent {
+            config.output_directory = config.transparent_directory.clone();
+            config.manifest_path = with_suffix(&config.manifest_path, "_transparent");
+            config.report_path = with_suffix(&config.report_path, "_transparent");
+
+            for rendition in &mut config.renditions { rendition.directory = format!("{}_transparent", rendition.directory.trim_end_matches('/')); }
+        }
+
+        if args.shard.is_some() && args.command.is_some() { exit_with_error("--shard can't be used with merge"); }
+        if let Some(shard) = &args.shard { config = shard.config(&config); }
+
+        config
+    }
+
+    // JPEGs can't have an alpha channel so transparent images are always PNGs.
+    // The main output is a JPEG unless it's transparent or the jpeg_quality is 0.
+    // JPEG renditions of transparent cards are PNGs too so they keep their alpha.
+    pub fn format(&self) -> RenditionFormat {
+        match self.rendition_format {
+            Some(RenditionFormat::Jpeg) | None if self.transparent || self.jpeg_quality == 0 => RenditionFormat::Png,
+            Some(format) => format,
+            None => RenditionFormat::Jpeg,
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self.format() {
+            RenditionFormat::Jpeg => ".jpeg",
+            RenditionFormat::Png => ".png",
+            RenditionFormat::Webp => ".webp",
+            RenditionFormat::Avif => ".avif",
+        }
+    }
+
+    // Each rendition is written like the main output but with its own directory,
+    // size and format. The jpeg_quality is the rendition's quality in any format.
+    pub fn rendition_configs(&self) -> Vec<Config> {
+        self.renditions.iter().map(|rendition| Config {
+            output_directory: rendition.directory.clone(),
+            output_width: rendition.width,
+            output_height: rendition.height,
+            jpeg_quality: rendition.quality,
+            renditions: vec![],
+            rendition_format: Some(rendition.format),
+            ..self.clone()
+        }).collect()
+    }
+
+    // The main output followed by the renditions.
+    pub fn outputs(&self) -> Vec<Config> {
+        [vec![self.clone()], self.rendition_configs()].concat()
+    }
+
+    pub fn ready_timeout(&self, card_type: CardType) -> Duration {
+        let seconds = self.ready_timeout_per_type.iter()
+            .find(|(name, _)| CardType::from_name(name) == Some(card_type))
+            .map_or(self.ready_timeout, |(_, &seconds)| seconds);
+
+        Duration::from_secs(seconds)
+    }
+
+    // The size of the page in CSS pixels.
+    pub fn viewport_size(&self) -> (u32, u32) {
+        (self.capture_width / self.device_scale_factor, self.capture_height / self.device_scale_factor)
+    }
+
+    pub fn navigation_timeout(&self) -> Duration { Duration::from_secs(self.navigation_timeout) }
+    pub fn screenshot_timeout(&self) -> Duration { Duration::from_secs(self.screenshot_timeout) }
+    pub fn stall_timeout(&self) -> Duration { Duration::from_secs(self.stall_timeout) }
+
+    pub fn card_url(&self, token_id: impl std::fmt::Display) -> String {
+        self.url_template.replace("{origin}", &self.origin).replace("{id}", &token_id.to_string())
+    }
+}
+
+// E.g. card_images_manifest.tsv becomes card_images_manifest_transparent.tsv
+pub fn with_suffix(path: &str, suffix: &str) -> String {
+    match path.rsplit_once('.') {
+        Some((stem, extension)) if !extension.contains('/') => format!("{}{}.{}", stem, suffix, extension),
+        _ => format!("{}{}", path, suffix),
+    }
+}
+
+pub fn exit_with_error(message: &str) -> ! {
+    eprintln!("\n{}\n", message);
+    std::process::exit(1);
+}
@@ -0,0 +1,82 @@
+use headless_chrome::protocol::{Event, Method, logs::events::LogEntryLevel};
+use serde::{Deserialize, Serialize};
+
+// The headless_chrome crate doesn't wrap these DevTools methods so they're
+// defined here in the same way as its own protocol module.
+
+#[derive(Deserialize, Debug)]
+pub struct EmptyReturnObject {}
+
+// Fixes the viewport size and pixel density regardless of the host's display.
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SetDeviceMetricsOverride {
+    pub width: u32,
+    pub height: u32,
+    pub device_scale_factor: f64,
+    pub mobile: bool,
+}
+
+impl Method for SetDeviceMetricsOverride {
+    const NAME: &'static str = "Emulation.setDeviceMetricsOverride";
+    type ReturnObject = EmptyReturnObject;
+}
+
+// Pages without a background of their own are drawn over this color.
+#[derive(Serialize, Debug)]
+pub struct SetDefaultBackgroundColorOverride {
+    pub color: Rgba,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Rgba {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+    pub a: f64,
+}
+
+impl Method for SetDefaultBackgroundColorOverride {
+    const NAME: &'static str = "Emulation.setDefaultBackgroundColorOverride";
+    type ReturnObject = EmptyReturnObject;
+}
+
+// Runs the script in every page the tab loads before the page's own scripts.
+#[derive(Serialize, Debug)]
+pub struct AddScriptToEvaluateOnNewDocument {
+    pub source: String,
+}
+
+impl Method for AddScriptToEvaluateOnNewDocument {
+    const NAME: &'static str = "Page.addScriptToEvaluateOnNewDocument";
+    type ReturnObject = EmptyReturnObject; // The script's identifier isn't needed.
+}
+
+// Closes the tab that the method is called on.
+#[derive(Serialize, Debug)]
+pub struct ClosePage {}
+
+impl Method for ClosePage {
+    const NAME: &'static str = "Page.close";
+    type ReturnObject = EmptyReturnObject;
+}
+
+// Errors the page logged, e.g. an image that 404'd or a script that threw.
+// console.error calls arrive as Runtime.consoleAPICalled events, which
+// headless_chrome has no variant for, so the page collects those itself.
+pub fn page_error(event: &Event) -> Option<String> {
+    match event {
+        Event::LogEntryAdded(event) if event.params.entry.level == LogEntryLevel::Error => {
+            let entry = &event.params.entry;
+            Some(entry.url.as_ref().map_or(entry.text.clone(), |url| format!("{} ({})", entry.text, url)))
+        },
+        Event::RuntimeExceptionThrown(event) => {
+            let details = &event.params.exception_details;
+            let description = details.exception.as_ref().and_then(|e| e.description.as_deref()).unwrap_or(&details.text);
+
+            // The description is followed by the stack trace.
+            Some(description.lines().next().unwrap_or_default().to_string())
+        },
+        _ => None,
+    }
+}
@@ -0,0 +1,63 @@
+use std::io::Cursor;
+use image::{io::Reader, imageops::FilterType, DynamicImage, ImageFormat, GenericImageView, RgbaImage};
+use crate::{capture::Screenshot, checks::{check_screenshot, LuminanceNorms}, config::Config, output};
+
+// Runs separately from the tabs so that Chrome can load the next card while
+// the previous screenshot is checked, resized and written. Returns the total
+// size of the image files, including any renditions.
+pub fn encode_screenshot(config: &Config, norms: &LuminanceNorms, screenshot: Screenshot) -> Result<usize, String> {
+    let Screenshot { token_id, card, clip, png_bytes, page_errors, .. } = screenshot;
+    let (viewport_width, viewport_height) = config.viewport_size();
+
+    if config.fail_on_page_errors && !page_errors.is_empty() {
+        return Err(format!("The page logged {} errors, the first was: {}", page_errors.len(), page_errors[0]));
+    }
+
+    if clip.x < 0. || clip.y < 0. || clip.x + clip.width > viewport_width as f64 || clip.y + clip.height > viewport_height as f64 {
+        return Err(format!("The {}x{} card at ({}, {}) doesn't fit in the {}x{} viewport", clip.width, clip.height, clip.x, clip.y, viewport_width, viewport_height));
+    }
+
+    // Capture at a higher resolution then downsample to produce a higher quality result.
+    let png_image = Reader::with_format(Cursor::new(png_bytes), ImageFormat::Png).decode().map_err(|e| format!("The screenshot could not be decoded: {}", e))?;
+
+    let expected_width = clip.width as u32 * config.device_scale_factor;
+    let expected_height = clip.height as u32 * config.device_scale_factor;
+
+    if png_image.dimensions() != (expected_width, expected_height) {
+        return Err(format!("The screenshot is {}x{} but the card is {}x{}", png_image.width(), png_image.height(), expected_width, expected_height));
+    }
+
+    check_screenshot(&png_image, &card, norms, config)?;
+
+    // Renditions are resized from the screenshot rather than the main output so they're as sharp as possible.
+    config.outputs().iter().map(|output| resize_and_write(output, token_id, &png_image)).sum()
+}
+
+fn resize_and_write(config: &Config, token_id: u128, image: &DynamicImage) -> Result<usize, String> {
+    // This preserves the card's aspect ratio so only one of the dimensions will match.
+    let image = if config.transparent { resize_with_alpha(image, config) } else {
+        image.resize(config.output_width, config.output_height, FilterType::Lanczos3)
+    };
+
+    if !output::fits_output_size(config, image.dimensions()) {
+        return Err(format!("The resized image is {}x{} which doesn't fit {}x{}", image.width(), image.height(), config.output_width, config.output_height));
+    }
+
+    output::write_image(config, token_id, &image)
+}
+
+// Resizing blends the transparent pixels' color into the card's edges unless
+// the color is premultiplied by the alpha first.
+fn resize_with_alpha(image: &DynamicImage, config: &Config) -> DynamicImage {
+    let mut rgba = image.to_rgba8();
+    rgba.pixels_mut().for_each(|p| (0..3).for_each(|i| p[i] = (p[i] as u32 * p[3] as u32 / 255) as u8));
+
+    let resized = DynamicImage::ImageRgba8(rgba).resize(config.output_width, config.output_height, FilterType::Lanczos3);
+    let mut rgba: RgbaImage = resized.into_rgba8();
+
+    for p in rgba.pixels_mut() {
+        if p[3] > 0 { (0..3).for_each(|i| p[i] = (p[i] as u32 * 255 / p[3] as u32).min(255) as u8); }
+    }
+
+    DynamicImage::ImageRgba8(rgba)
+}
@@ -0,0 +1,210 @@
+use std::{collections::{BTreeMap, VecDeque}, fs, path::PathBuf, process, sync::{Mutex, atomic::{AtomicBool, AtomicUsize, Ordering}}, thread, time::{Duration, Instant}};
+use headless_chrome::protocol::page::Viewport;
+use image::{DynamicImage, ImageOutputFormat, Rgba, RgbaImage};
+use puzzle_card::PuzzleCard;
+use crate::{capture::{CardCapturer, Screenshot}, config::Config, report::Timings, watchdog::Watchdog};
+
+// A deterministic stand-in for Chrome so the orchestration can be tested
+// offline. Each card is captured successfully unless it's scripted to behave
+// differently on its next attempts.
+
+pub const STEP_TIMEOUT: Duration = Duration::from_secs(1);
+pub const PAGE_ERROR: &str = "Failed to load resource: the server responded with a status of 404 (Not Found)";
+
+#[derive(Clone, Copy, Debug)]
+pub enum Behaviour {
+    Succeed,
+    Fail,             // The page logs an error and fails to load.
+    Hang(Duration),   // The page loads slowly and overruns STEP_TIMEOUT if longer, unless it's killed.
+    Blank,            // The screenshot is a single color so the checks reject it.
+    PageError,        // The page logs an error but otherwise renders correctly.
+    Panic,            // The capturer panics, e.g. on a bug in headless_chrome.
+}
+
+#[derive(Default)]
+pub struct FakeCapturer {
+    scripts: Mutex<BTreeMap<u128, VecDeque<Behaviour>>>,
+    attempts: Mutex<BTreeMap<u128, usize>>,
+    page_errors: Mutex<BTreeMap<usize, Vec<String>>>, // By tab.
+    generation: AtomicUsize,
+    num_tabs_opened: AtomicUsize,
+    num_tabs_closed: AtomicUsize,
+    num_kills: AtomicUsize,
+    close_fails: AtomicBool,
+    restart_fails: AtomicBool,
+    crashed: AtomicBool,
+    transparent: AtomicBool,
+}
+
+impl FakeCapturer {
+    pub fn script(&self, token_id: u128, behaviours: &[Behaviour]) {
+        self.scripts.lock().unwrap().entry(token_id).or_default().extend(behaviours);
+    }
+
+    // Closing a tab fails like it does when Chrome itself is stuck.
+    pub fn set_close_fails(&self, close_fails: bool) {
+        self.close_fails.store(close_fails, Ordering::SeqCst);
+    }
+
+    // Chrome crashes the next time it's restarted and can't be launched again.
+    pub fn set_restart_fails(&self, restart_fails: bool) {
+        self.restart_fails.store(restart_fails, Ordering::SeqCst);
+    }
+
+    // The corners are see-through like they are with a transparent background.
+    pub fn set_transparent(&self, transparent: bool) {
+        self.transparent.store(transparent, Ordering::SeqCst);
+    }
+
+    pub fn attempts(&self, token_id: u128) -> usize {
+        self.attempts.lock().unwrap().get(&token_id).copied().unwrap_or(0)
+    }
+
+    pub fn num_tabs_opened(&self) -> usize {
+        self.num_tabs_opened.load(Ordering::SeqCst)
+    }
+
+    pub fn num_tabs_closed(&self) -> usize {
+        self.num_tabs_closed.load(Ordering::SeqCst)
+    }
+
+    pub fn num_kills(&self) -> usize {
+        self.num_kills.load(Ordering::SeqCst)
+    }
+
+    // Returns early if the watchdog kills it, like a DevTools call does.
+    fn hang(&self, duration: Duration) -> Result<(), &'static str> {
+        let started = Instant::now();
+
+        while started.elapsed() < duration {
+            if self.crashed.load(Ordering::SeqCst) { return Err("Connection closed"); }
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        Ok(())
+    }
+}
+
+impl CardCapturer for FakeCapturer {
+    type Tab = usize;
+
+    fn index(&self) -> usize {
+        0
+    }
+
+    fn generation(&self) -> usize {
+        self.generation.load(Ordering::SeqCst)
+    }
+
+    // Like Chrome, it crashes until it's restarted.
+    fn kill(&self, generation: usize) {
+        if generation != self.generation() { return; }
+
+        self.num_kills.fetch_add(1, Ordering::SeqCst);
+        self.crashed.store(true, Ordering::SeqCst);
+    }
+
+    fn new_tab(&self) -> Result<(usize, usize), String> {
+        if self.crashed.load(Ordering::SeqCst) { return Err("Could not open a tab: Chrome isn't running".to_string()); }
+        Ok((self.num_tabs_opened.fetch_add(1, Ordering::SeqCst), self.generation()))
+    }
+
+    fn close_tab(&self, _tab: &usize) -> Result<(), String> {
+        if self.close_fails.load(Ordering::SeqCst) { return Err("Could not close the tab: timed out".to_string()); }
+
+        self.num_tabs_closed.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn restart(&self, generation: usize) -> Result<(), String> {
+        if self.restart_fails.load(Ordering::SeqCst) {
+            self.crashed.store(true, Ordering::SeqCst);
+            return Err("Could not launch Chrome: timed out".to_string());
+        }
+
+        if self.generation.compare_exchange(generation, generation + 1, Ordering::SeqCst, Ordering::SeqCst).is_ok() { self.crashed.store(false, Ordering::SeqCst); }
+        Ok(())
+    }
+
+    fn take_page_errors(&self, tab: &usize) -> Vec<String> {
+        self.page_errors.lock().unwrap().remove(tab).unwrap_or_default()
+    }
+
+    fn capture_card(&self, tab: &usize, watchdog: &Watchdog, slot: usize, token_id: u128) -> Result<Screenshot, String> {
+        let behaviour = self.scripts.lock().unwrap().get_mut(&token_id).and_then(VecDeque::pop_front).unwrap_or(Behaviour::Succeed);
+        *self.attempts.lock().unwrap().entry(token_id).or_default() += 1;
+        let started = Instant::now();
+
+        let page_errors = if matches!(behaviour, Behaviour::Fail | Behaviour::PageError) { vec![PAGE_ERROR.to_string()] } else { vec![] };
+        self.page_errors.lock().unwrap().insert(*tab, page_errors.clone());
+
+        watchdog.run_step(slot, token_id, "Navigation", STEP_TIMEOUT, || match behaviour {
+            _ if self.crashed.load(Ordering::SeqCst) => Err("Connection closed"),
+            Behaviour::Fail => Err("Connection closed"),
+            Behaviour::Panic => panic!("The fake capturer panicked"),
+            Behaviour::Hang(duration) => self.hang(duration),
+            _ => Ok(()),
+        })?;
+
+        let card = PuzzleCard::from_token_id(token_id).unwrap();
+        let clip = Viewport { x: 25., y: 5., width: CARD_WIDTH as f64, height: CARD_HEIGHT as f64, scale: 1. };
+
+        let timings = Timings { navigation: started.elapsed(), ..Timings::default() };
+        self.page_errors.lock().unwrap().remove(tab);
+
+        Ok(Screenshot { token_id, card, clip, png_bytes: synthetic_png(matches!(behaviour, Behaviour::Blank), self.transparent.load(Ordering::SeqCst)), timings, page_errors })
+    }
+}
+
+// The size of the card in CSS pixels. It fits in test_config's 150x150 viewport.
+const CARD_WIDTH: u32 = 100;
+const CARD_HEIGHT: u32 = 140;
+
+// A card that passes the checks: the background in its rounded corners, sharp
+// text at the top and a gradient elsewhere.
+fn synthetic_png(blank: bool, transparent: bool) -> Vec<u8> {
+    let (width, height) = (CARD_WIDTH * 2, CARD_HEIGHT * 2);
+    let radius = width / 30;
+
+    let image = RgbaImage::from_fn(width, height, |x, y| {
+        let in_corner = (x < radius || x >= width - radius) && (y < radius || y >= height - radius);
+        let in_text = y >= height * 3 / 100 && y < height * 12 / 100;
+
+        if blank { Rgba([255, 255, 255, 255]) }
+        else if in_corner && transparent { Rgba([0, 0, 0, 0]) }
+        else if in_corner { Rgba([20, 60, 20, 255]) }
+        else if in_text { if (x + y) % 2 == 0 { Rgba([0, 0, 0, 255]) } else { Rgba([255, 255, 255, 255]) } }
+        else { Rgba([200, (y * 255 / height) as u8, 150, 255]) }
+    });
+
+    let mut bytes = vec![];
+    DynamicImage::ImageRgba8(image).write_to(&mut bytes, ImageOutputFormat::Png).unwrap();
+    bytes
+}
+
+// Writes to a fresh directory per test so that tests can run in parallel.
+pub fn test_config(name: &str) -> Config {
+    let directory = std::env::temp_dir().join(format!("generate_images_test_{}_{}", name, process::id()));
+    let _ = fs::remove_dir_all(&directory);
+
+    let output_directory = directory.join("card_images");
+    fs::create_dir_all(&output_directory).unwrap();
+
+    let path = |p: PathBuf| p.to_str().unwrap().to_string();
+
+    Config {
+        capture_width: 300,
+        capture_height: 300,
+        output_width: 70,
+        output_height: 70,
+        output_directory: path(output_directory),
+        manifest_path: path(directory.join("manifest.tsv")),
+        report_path: path(directory.join("report.jsonl")),
+        metadata_directory: path(directory.join("metadata_api")),
+        site_directory: path(directory.join("site")),
+        num_threads: 1,
+        tabs_per_instance: 2,
+        encode_threads: 1,
+        ..Config::default()
+    }
+}
@@ -1,225 +1,280 @@
-use headless_chrome::{Browser, LaunchOptionsBuilder, protocol::page::ScreenshotFormat, Tab};
-use std::{fs, collections::BTreeSet, sync::Arc, sync::atomic::{AtomicUsize, Ordering}, time::Duration, thread};
-use std::io::{Cursor, Write};
-use image::{io::Reader, imageops::FilterType, ImageFormat, jpeg::JpegEncoder, GenericImageView};
-use crossbeam_queue::ArrayQueue;
+use std::{fs, path::Path, collections::{BTreeSet, BTreeMap}, sync::Arc};
+use clap::Parser;
+use puzzle_card::{PuzzleCard, CardCounts};
+use capture::ChromeInstance;
+use config::{Args, Command, Config, exit_with_error};
+use manifest::Manifest;
+use pipeline::MAX_CHECK_ATTEMPTS;
+use output::token_ids_from_output_directory;
+use server::StaticServer;
+use watchdog::Watchdog;
+
+mod capture;
+mod checks;
+mod config;
+mod devtools;
+mod encode;
+#[cfg(test)]
+mod fake_capturer;
+mod manifest;
+mod merge;
+mod output;
+mod pipeline;
+mod progress;
+mod report;
+mod run;
+mod server;
+mod shard;
+mod throughput;
+mod watchdog;
+
+// How many card names --dry-run prints for each group of images.
+const NUM_EXAMPLES: usize = 5;
 
-const CAPTURE_WIDTH: u32 = 1050;
-const CAPTURE_HEIGHT: u32 = 1050;
-
-const OUTPUT_WIDTH: u32 = 350;
-const OUTPUT_HEIGHT: u32 = 350;
+fn main() {
+    let args = Args::parse();
+    let mut config = Config::load(&args);
+    let (only, dry_run, shard) = (args.only, args.dry_run, args.shard);
 
-const OUTPUT_DIRECTORY: &str = "../../public_s3/card_images";
-const JPEG_QUALITY: Option<u8> = Some(75); // Or output a lossless PNG if None.
+    // Enumerated once because there are a million cards.
+    let cards = PuzzleCard::all().collect::<Vec<_>>();
+    let all_token_ids = cards.iter().map(|card| card.token_id()).collect::<BTreeSet<_>>();
 
-const MENU_BAR_HEIGHT: u32 = 124;
-const NUM_THREADS: u32 = 4;
+    if let Some(Command::Merge { shards }) = args.command {
+        return merge::merge_shards(&config, shards, &all_token_ids).unwrap_or_else(|e| exit_with_error(&e));
+    }
 
-fn main() {
-    fs::create_dir_all(OUTPUT_DIRECTORY).unwrap();
-    let extension = if JPEG_QUALITY.is_some() { ".jpeg" } else { ".png" };
+    println!("{}", CardCounts::of(cards.iter().copied()));
 
-    let expected_token_ids = token_ids_from_metadata_directory();
-    let actual_token_ids = token_ids_from_output_directory(extension);
+    // The metadata isn't needed to capture images but check it agrees with the cards.
+    if Path::new(&config.metadata_directory).exists() {
+        let metadata_token_ids = token_ids_from_metadata_directory(&config);
 
-    let missing_token_ids = expected_token_ids.difference(&actual_token_ids).collect::<Vec<_>>();
-    let surplus_token_ids = actual_token_ids.difference(&expected_token_ids).collect::<Vec<_>>();
+        check_token_ids_are_valid(&metadata_token_ids);
+        check_metadata_matches_cards(&metadata_token_ids, &all_token_ids);
+    }
 
-    if missing_token_ids.is_empty() { println!("All images already captured. Exiting."); return; }
+    // Other shards' cards count as surplus in this shard's directory.
+    let in_shard = |token_id: &u128| shard.is_none_or(|s| s.contains(*token_id));
+    let expected_token_ids = all_token_ids.iter().copied().filter(in_shard).collect::<BTreeSet<_>>();
 
-    let queue = Arc::new(ArrayQueue::new(missing_token_ids.len()));
-    missing_token_ids.iter().for_each(|t| queue.push(**t).unwrap());
+    if let Some(shard) = shard { println!("Shard {} has {} of the {} cards.\n", shard, expected_token_ids.len(), all_token_ids.len()); }
 
-    surplus_token_ids.iter().for_each(|t| fs::remove_file(format!("{}/{}{}", OUTPUT_DIRECTORY, t, extension)).unwrap());
-    if !surplus_token_ids.is_empty() { println!("\nRemoved {} images that have no corresponding metadata.", surplus_token_ids.len()); }
+    let mut actual_token_ids = token_ids_from_output_directory(&config);
 
-    println!("\nCapturing at {}x{} then resizing to {}x{}.", CAPTURE_WIDTH, CAPTURE_HEIGHT, OUTPUT_WIDTH, OUTPUT_HEIGHT);
-    println!("\n{}/{} images already captured.\n", expected_token_ids.len() - missing_token_ids.len(), expected_token_ids.len());
+    // Cards are re-captured if any of their renditions are missing. Surplus images
+    // are only looked for in the main output directory.
+    for rendition in config.rendition_configs() {
+        let rendition_token_ids = token_ids_from_output_directory(&rendition);
+        actual_token_ids.retain(|t| rendition_token_ids.contains(t) || !expected_token_ids.contains(t));
+    }
 
-    let num_captured = Arc::new(AtomicUsize::new(0));
-    let num_total = queue.len();
+    // Treat images that are corrupt or the wrong size as missing so they're re-captured.
+    if config.verify_existing {
+        let existing_token_ids = actual_token_ids.intersection(&expected_token_ids).copied().collect::<Vec<_>>();
 
-    let mut threads = (0..NUM_THREADS).map(|i| {
-        let queue = Arc::clone(&queue);
-        let num_captured = Arc::clone(&num_captured);
+        for output in config.outputs() {
+            let invalid = output::scan_existing_images(&output, &existing_token_ids);
 
-        thread::spawn(move || {
-            let (mut chrome, mut tab) = new_instance_of_chrome_with_one_tab();
+            for (token_id, reason) in &invalid {
+                println!("Re-queueing {}: {}", PuzzleCard::describe(*token_id), reason);
+                actual_token_ids.remove(token_id);
+            }
 
-            let mut next_token_id = queue.pop();
-            let mut preloaded = false;
+            println!("\n{} of {} existing images in {} are invalid.\n", invalid.len(), existing_token_ids.len(), output.output_directory);
+        }
+    }
 
-            loop {
-                let token_id = match next_token_id { Some(t) => t, _ => break };
-                next_token_id = queue.pop();
+    let missing_token_ids = expected_token_ids.difference(&actual_token_ids).copied().collect::<Vec<_>>();
+    let surplus_token_ids = actual_token_ids.difference(&expected_token_ids).copied().collect::<Vec<_>>();
+    let existing_token_ids = actual_token_ids.intersection(&expected_token_ids).copied().collect::<BTreeSet<_>>();
 
-                loop {
-                    let (success, preloaded_next) = capture_screenshot_of_card_page(&tab, token_id, &extension, preloaded, next_token_id);
-                    preloaded = preloaded_next;
+    // Re-capture images whose metadata, site build or capture settings have changed.
+    let manifest = Arc::new(Manifest::load(&config, &expected_token_ids));
 
-                    if success {
-                        break;
-                    } else {
-                        println!("Chrome instance {} is stuck, restarting...", i);
+    let stale_token_ids = existing_token_ids.iter().copied().filter(|&t| manifest.has_changed(t) == Some(true)).collect::<Vec<_>>();
+    let num_unrecorded = existing_token_ids.iter().filter(|&&t| manifest.has_changed(t).is_none()).count();
 
-                        drop(tab); drop(chrome);
-                        let (a, b) = new_instance_of_chrome_with_one_tab();
-                        chrome = a; tab = b;
-                    }
-                }
+    // Re-capture every card that matches --only, even if its image already exists.
+    let token_ids_to_capture = match &only {
+        Some(filter) => cards.iter().filter(|card| filter.matches(card)).map(|card| card.token_id()).filter(in_shard).collect(),
+        None => missing_token_ids.iter().chain(&stale_token_ids).copied().collect::<Vec<_>>(),
+    };
 
-                let previous = num_captured.fetch_add(1, Ordering::Relaxed);
-                println!("Captured {}/{}", previous + 1, num_total);
-            }
-        })
-    }).collect::<Vec<_>>();
+    // Leave other images alone when only capturing some of the cards.
+    let surplus_token_ids = if only.is_some() { vec![] } else { surplus_token_ids };
+    let surplus_percent = 100. * surplus_token_ids.len() as f64 / actual_token_ids.len().max(1) as f64;
+    let too_many_surplus = surplus_percent > config.max_surplus_percent;
 
-    for thread in threads.drain(..) {
-        thread.join().unwrap();
-    }
-}
+    if dry_run {
+        print_examples("are missing", &missing_token_ids);
+        print_examples("have inputs that changed", &stale_token_ids);
+        print_examples("have no corresponding card and would be quarantined", &surplus_token_ids);
 
-fn new_instance_of_chrome_with_one_tab() -> (Browser, Arc<Tab>) {
-    let options = LaunchOptionsBuilder::default()
-        .headless(false) // Otherwise, it tends to time out.
-        .window_size(Some((CAPTURE_WIDTH / 2, CAPTURE_HEIGHT / 2 + MENU_BAR_HEIGHT)))
-        .idle_browser_timeout(Duration::from_secs(999999999))
-        .build().unwrap();
+        if only.is_some() { println!("\n{} images match --only.", token_ids_to_capture.len()); }
+        if too_many_surplus { println!("\nThis would be refused because {:.1}% of images are surplus, more than the max_surplus_percent of {}%.", surplus_percent, config.max_surplus_percent); }
 
-    let chrome = Browser::new(options).unwrap();
-    let tab = chrome.wait_for_initial_tab().unwrap();
+        println!("\nDry run, so nothing was captured or quarantined.");
+        return;
+    }
 
-    tab.set_default_timeout(Duration::from_secs(999999999));
-    check_if_server_running(&tab);
+    // Refused before anything is written so the manifest still records the surplus images.
+    if too_many_surplus {
+        exit_with_error(&format!("{} of {} images ({:.1}%) have no corresponding card, more than the max_surplus_percent of {}%.\nCheck the cards and metadata are right or raise --max-surplus-percent to quarantine them.", surplus_token_ids.len(), actual_token_ids.len(), surplus_percent, config.max_surplus_percent));
+    }
 
-    (chrome, tab)
-}
+    for output in config.outputs() {
+        fs::create_dir_all(&output.output_directory).unwrap();
+        output::remove_partial_files(&output);
+    }
 
-fn token_ids_from_metadata_directory() -> BTreeSet<u128> {
-    let mut token_ids = BTreeSet::new();
+    manifest.compact(&existing_token_ids);
 
-    for result in fs::read_dir("../../public_s3/metadata_api").unwrap() {
-        let dir_entry = result.unwrap();
+    if !surplus_token_ids.is_empty() {
+        let directory = output::quarantine_images(&config, &surplus_token_ids);
+        println!("\nMoved {} images that have no corresponding card to {}\n", surplus_token_ids.len(), directory);
+    }
 
-        let metadata = dir_entry.metadata().unwrap();
-        if !metadata.is_file() { continue; }
+    if token_ids_to_capture.is_empty() {
+        if only.is_some() { println!("No cards match --only. Exiting."); } else { println!("All images already captured. Exiting."); }
+        return;
+    }
 
-        let file_name = dir_entry.file_name().into_string().unwrap();
-        if !file_name.contains(".json") { continue; }
+    // Serve the exported website for the duration of the run unless one is already running.
+    let server = if config.external_server { None } else {
+        let server = StaticServer::start(&config.site_directory).unwrap_or_else(|e| exit_with_error(&e));
+        config.origin = server.origin.clone();
 
-        let hex_string = file_name.split(".json").next().unwrap();
-        if hex_string.len() != 64 { continue; }
+        println!("Serving {} at {}", config.site_directory, server.origin);
+        Some(server)
+    };
 
-        let tail_of_string = &hex_string[32..];
-        let token_id = u128::from_str_radix(tail_of_string, 16).unwrap();
+    let config = Arc::new(config);
 
-        token_ids.insert(token_id);
-    }
+    println!("\nCapturing cards in a {}x{} viewport then resizing them to fit {}x{}.", config.capture_width, config.capture_height, config.output_width, config.output_height);
+    println!("Running {} Chrome instances with {} tabs each.", config.num_threads, config.tabs_per_instance);
+    println!("\n{}/{} images already captured.", expected_token_ids.len() - missing_token_ids.len(), expected_token_ids.len());
+    println!("Site build {}.", manifest.build_id().unwrap_or("unknown"));
 
-    return token_ids;
-}
+    if only.is_none() && !stale_token_ids.is_empty() { println!("Re-capturing {} images whose inputs have changed.", stale_token_ids.len()); }
+    if num_unrecorded > 0 { println!("{} images aren't in {} so can't be checked for changes. Use --only to re-capture them.", num_unrecorded, config.manifest_path); }
+    if only.is_some() { println!("Capturing {} images that match --only.", token_ids_to_capture.len()); }
+    println!();
 
-fn token_ids_from_output_directory(extension: &'static str) -> BTreeSet<u128> {
-    let mut token_ids = BTreeSet::new();
+    let (pipeline, watchdog) = run::capture_cards(&config, &token_ids_to_capture, &manifest, {
+        let config = Arc::clone(&config);
+        move |i| Arc::new(ChromeInstance::launch(i, Arc::clone(&config)))
+    });
 
-    for result in fs::read_dir(OUTPUT_DIRECTORY).unwrap() {
-        let dir_entry = result.unwrap();
+    if let Some(server) = server { server.stop(); }
 
-        let metadata = dir_entry.metadata().unwrap();
-        if !metadata.is_file() { continue; }
+    println!("\n{}", pipeline.throughput);
+    print_summary_of_rejections(&pipeline.rejections());
+    print_summary_of_restarts(&watchdog);
 
-        let file_name = dir_entry.file_name().into_string().unwrap();
-        if !file_name.contains(extension) { continue; }
+    if pipeline.num_unfinished() > 0 {
+        println!("\nThe run stopped early with {} cards unfinished. Re-run to capture them.", pipeline.num_unfinished());
+    }
+}
 
-        let token_id_string = file_name.split(extension).next().unwrap();
+fn print_examples(description: &str, token_ids: &[u128]) {
+    println!("\n{} images {}", token_ids.len(), description);
+    if token_ids.is_empty() { return; }
 
-        let token_id = token_id_string.parse::<u128>().unwrap();
-        token_ids.insert(token_id);
+    for &token_id in token_ids.iter().take(NUM_EXAMPLES) {
+        println!("  - {}", PuzzleCard::describe(token_id));
     }
 
-    token_ids
+    if token_ids.len() > NUM_EXAMPLES { println!("  - and {} more", token_ids.len() - NUM_EXAMPLES); }
 }
 
-fn check_if_server_running(tab: &Arc<Tab>) {
-    let mut success = false;
+fn print_summary_of_rejections(rejections: &BTreeMap<u128, Vec<String>>) {
+    if rejections.is_empty() { return; }
 
-    // This also seems to fix the first captured image sometimes not having its
-    // text scaled correctly so it's worth doing all 3 iterations.
-    for i in 0..3 {
-        if let Err(_) = tab.navigate_to(&format!("http://localhost:5000/card?tokenID={}&referrer=generate_images", i)) { continue; }
-        if let Err(_) = tab.wait_until_navigated() { continue; }
+    let num_requeued = rejections.values().map(|reasons| reasons.len().min(MAX_CHECK_ATTEMPTS - 1)).sum::<usize>();
+    let failed = rejections.iter().filter(|(_, reasons)| reasons.len() >= MAX_CHECK_ATTEMPTS).collect::<Vec<_>>();
 
-        success = true;
-    }
+    println!("\n{} screenshots failed a check and were re-queued.", num_requeued);
+    if failed.is_empty() { return; }
 
-    if !success {
-        eprintln!("\nNo server running. Start it with ./bin/serve_website_static\n");
-        std::process::exit(1);
+    println!("\n{} cards still failed after {} attempts so weren't saved:", failed.len(), MAX_CHECK_ATTEMPTS);
+
+    for (&token_id, reasons) in failed {
+        println!("  - {}: {}", PuzzleCard::describe(token_id), reasons.last().unwrap());
     }
 }
 
-fn capture_screenshot_of_card_page(tab: &Arc<Tab>, token_id: u128, extension: &str, mut preloaded: bool, next_token_id: Option<u128>) -> (bool, bool) {
-    let mut attempts = 3;
+fn print_summary_of_restarts(watchdog: &Watchdog) {
+    let restarts = watchdog.restarts();
+    if restarts.is_empty() { return; }
 
-    loop {
-        if attempts == 0 { return (false, false); }
-        attempts -= 1;
+    println!("\nTabs got stuck {} times:", restarts.len());
 
-        if !preloaded {
-            if let Err(_) = tab.navigate_to(&format!("http://localhost:5000/card?tokenID={}&referrer=generate_images", token_id)) { continue; }
-        }
+    for restart in restarts.iter() {
+        let card = restart.token_id.map_or("no card".to_string(), PuzzleCard::describe);
+        println!("  - {} on {}: {}", watchdog.describe_slot(restart.slot), card, restart.reason);
+    }
+}
 
-        if let Err(_) = tab.wait_until_navigated() { preloaded = false; continue; }
+fn token_ids_from_metadata_directory(config: &Config) -> BTreeSet<u128> {
+    let mut token_ids = BTreeSet::new();
+    let mut num_invalid = 0;
 
-        let png_bytes = match tab.capture_screenshot(ScreenshotFormat::PNG, None, true) {
-            Ok(png_bytes) => png_bytes,
-            Err(_) => { preloaded = false; continue; }, // Restart this loop iteration to try again.
-        };
+    for result in fs::read_dir(&config.metadata_directory).unwrap() {
+        let dir_entry = result.unwrap();
 
-        // Try to preload the next page in Chrome while we're processing the current screenshot.
-        let preloaded_next = match next_token_id {
-            Some(t) => tab.navigate_to(&format!("http://localhost:5000/card?tokenID={}&referrer=generate_images", t)).is_ok(),
-            None => false,
+        let metadata = dir_entry.metadata().unwrap();
+        if !metadata.is_file() { continue; }
+
+        let file_name = match dir_entry.file_name().into_string() {
+            Ok(file_name) => file_name,
+            Err(file_name) => { eprintln!("{:?} is not a valid file name", file_name); num_invalid += 1; continue; }
         };
 
-        // Give cloak cards longer to load.
-        if is_cloak(token_id) {
-            thread::sleep(Duration::from_secs(2));
+        let hex_string = match file_name.strip_suffix(".json") { Some(s) => s, _ => continue };
+        if hex_string == "contract" { continue; }
+
+        match PuzzleCard::token_id_from_metadata_id(hex_string) {
+            Ok(token_id) => { token_ids.insert(token_id); },
+            Err(error) => { eprintln!("{} is invalid: {}", file_name, error); num_invalid += 1; },
         }
+    }
 
-        // Capture at a higher resolution then downsample to produce a higher quality result.
-        let png_image = Reader::with_format(Cursor::new(png_bytes), ImageFormat::Png).decode().unwrap();
-        assert_eq!(png_image.width(), CAPTURE_WIDTH);
-        assert_eq!(png_image.height(), CAPTURE_HEIGHT);
+    if num_invalid > 0 {
+        eprintln!("\n{} files in {} are not named by token ID. Please remove them and re-run ./bin/generate_metadata\n", num_invalid, config.metadata_directory);
+        std::process::exit(1);
+    }
 
-        let png_image = png_image.resize(OUTPUT_WIDTH, OUTPUT_HEIGHT, FilterType::Lanczos3);
-        assert_eq!(png_image.width(), OUTPUT_WIDTH);
-        assert_eq!(png_image.height(), OUTPUT_HEIGHT);
+    token_ids
+}
 
-        let out_path = format!("{}/{}{}", OUTPUT_DIRECTORY, token_id, extension);
-        let mut file = std::fs::File::create(out_path).unwrap();
+fn check_token_ids_are_valid(token_ids: &BTreeSet<u128>) {
+    let mut num_invalid = 0;
 
-        if let Some(quality) = JPEG_QUALITY {
-            let mut jpeg_bytes = vec![];
+    for &token_id in token_ids {
+        let (name, errors) = match PuzzleCard::from_token_id(token_id) {
+            Ok(card) => (card.to_string(), card.validate().err().unwrap_or_default()),
+            Err(error) => ("unknown card".to_string(), vec![error.to_string()]),
+        };
 
-            let mut jpeg_encoder = JpegEncoder::new_with_quality(&mut jpeg_bytes, quality);
-            jpeg_encoder.encode_image(&png_image).unwrap();
+        if errors.is_empty() { continue; }
+        num_invalid += 1;
 
-            file.write_all(&jpeg_bytes).unwrap();
-        } else {
-            png_image.write_to(&mut file, image::ImageOutputFormat::Png).unwrap();
-        }
+        eprintln!("Token {:064x} ({}) is invalid:", token_id, name);
+        errors.iter().for_each(|e| eprintln!("  - {}", e));
+    }
 
-        return (true, preloaded_next);
+    if num_invalid > 0 {
+        eprintln!("\n{} metadata files have invalid token IDs. Please re-run ./bin/generate_metadata\n", num_invalid);
+        std::process::exit(1);
     }
 }
 
-fn is_cloak(token_id: u128) -> bool {
-    ((token_id >> 40) as u8) == 2
-}
+fn check_metadata_matches_cards(metadata_token_ids: &BTreeSet<u128>, expected_token_ids: &BTreeSet<u128>) {
+    let num_without_metadata = expected_token_ids.difference(metadata_token_ids).count();
+    let num_without_card = metadata_token_ids.difference(expected_token_ids).count();
 
-#[allow(dead_code)]
-fn is_master_copy(token_id: u128) -> bool {
-    ((token_id >> 48) as u8) == 6
+    if num_without_metadata + num_without_card > 0 {
+        eprintln!("Warning: {} cards have no metadata and {} metadata files aren't cards. Please re-run ./bin/generate_metadata\n", num_without_metadata, num_without_card);
+    }
 }
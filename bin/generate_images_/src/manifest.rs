@@ -0,0 +1,229 @@
+use std::{fs::{self, File, OpenOptions}, io::Write, path::Path, collections::{BTreeMap, BTreeSet}, sync::Mutex};
+use rayon::prelude::*;
+use puzzle_card::PuzzleCard;
+use crate::{config::{Config, exit_with_error}, output::write_atomically, progress::Progress};
+
+// Records what each image was captured from so that only images whose inputs
+// have changed are re-captured. Each line is tab-separated:
+//
+//   token_id  metadata_hash  site_build_id  settings_hash
+//
+// A '-' means the input wasn't available when the image was captured so it's
+// never treated as changed. Lines are appended as images are saved and later
+// lines replace earlier ones.
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Inputs {
+    metadata_hash: Option<u64>,
+    build_id: Option<String>,
+    settings_hash: u64,
+}
+
+pub struct Manifest {
+    path: String,
+    recorded: BTreeMap<u128, Inputs>,
+    metadata_hashes: BTreeMap<u128, u64>,
+    build_id: Option<String>,
+    settings_hash: u64,
+    file: Mutex<Option<File>>,
+}
+
+impl Manifest {
+    pub fn load(config: &Config, token_ids: &BTreeSet<u128>) -> Self {
+        let recorded = match fs::read_to_string(&config.manifest_path) {
+            Ok(content) => parse(&content, &config.manifest_path),
+            Err(_) => BTreeMap::new(),
+        };
+
+        Manifest {
+            path: config.manifest_path.clone(),
+            recorded,
+            metadata_hashes: hash_metadata(config, token_ids),
+            build_id: site_build_id(&config.site_directory),
+            settings_hash: settings_hash(config),
+            file: Mutex::new(None),
+        }
+    }
+
+    pub fn build_id(&self) -> Option<&str> {
+        self.build_id.as_deref()
+    }
+
+    // Returns None if the image was captured before the manifest recorded it.
+    // Inputs that weren't available when the image was captured or aren't on
+    // this run, e.g. the metadata, are unknown so they're ignored.
+    pub fn has_changed(&self, token_id: u128) -> Option<bool> {
+        let recorded = self.recorded.get(&token_id)?;
+        let current = self.current_inputs(token_id);
+
+        let metadata_changed = differs(&current.metadata_hash, &recorded.metadata_hash);
+        let build_changed = differs(&current.build_id, &recorded.build_id);
+
+        Some(metadata_changed || build_changed || current.settings_hash != recorded.settings_hash)
+    }
+
+    // Rewrites the file without duplicate lines or lines for images that no longer exist.
+    pub fn compact(&self, existing_token_ids: &BTreeSet<u128>) {
+        let mut content = String::new();
+
+        for (&token_id, inputs) in &self.recorded {
+            if existing_token_ids.contains(&token_id) { content.push_str(&format_line(token_id, inputs)); }
+        }
+
+        write_atomically(&self.path, content.as_bytes(), |_| Ok(())).unwrap_or_else(|e| exit_with_error(&e));
+    }
+
+    pub fn record(&self, token_id: u128) {
+        let mut file = self.file.lock().unwrap();
+
+        let file = file.get_or_insert_with(|| {
+            OpenOptions::new().create(true).append(true).open(&self.path).unwrap()
+        });
+
+        file.write_all(format_line(token_id, &self.current_inputs(token_id)).as_bytes()).unwrap();
+    }
+
+    fn current_inputs(&self, token_id: u128) -> Inputs {
+        Inputs {
+            metadata_hash: self.metadata_hashes.get(&token_id).copied(),
+            build_id: self.build_id.clone(),
+            settings_hash: self.settings_hash,
+        }
+    }
+}
+
+fn differs<T: PartialEq>(current: &Option<T>, recorded: &Option<T>) -> bool {
+    matches!((current, recorded), (Some(current), Some(recorded)) if current != recorded)
+}
+
+fn parse(content: &str, path: &str) -> BTreeMap<u128, Inputs> {
+    let mut recorded = BTreeMap::new();
+
+    for (i, line) in content.lines().enumerate() {
+        let fields = line.split('\t').collect::<Vec<_>>();
+
+        let parsed = match fields[..] {
+            [token_id, metadata_hash, build_id, settings_hash] => (|| Some((token_id.parse().ok()?, Inputs {
+                metadata_hash: if metadata_hash == "-" { None } else { Some(u64::from_str_radix(metadata_hash, 16).ok()?) },
+                build_id: if build_id == "-" { None } else { Some(build_id.to_string()) },
+                settings_hash: u64::from_str_radix(settings_hash, 16).ok()?,
+            })))(),
+            _ => None,
+        };
+
+        // The last line might be incomplete if the previous run was interrupted.
+        match parsed {
+            Some((token_id, inputs)) => { recorded.insert(token_id, inputs); },
+            None => eprintln!("Ignoring line {} of {} because it isn't valid", i + 1, path),
+        }
+    }
+
+    recorded
+}
+
+fn format_line(token_id: u128, inputs: &Inputs) -> String {
+    let metadata_hash = inputs.metadata_hash.map_or("-".to_string(), |h| format!("{:016x}", h));
+    let build_id = inputs.build_id.as_deref().unwrap_or("-");
+
+    format!("{}\t{}\t{}\t{:016x}\n", token_id, metadata_hash, build_id, inputs.settings_hash)
+}
+
+fn hash_metadata(config: &Config, token_ids: &BTreeSet<u128>) -> BTreeMap<u128, u64> {
+    if !Path::new(&config.metadata_directory).exists() { return BTreeMap::new(); }
+
+    let token_ids = token_ids.iter().copied().collect::<Vec<_>>();
+
+    let progress = Progress::new("Hashed metadata", token_ids.len());
+
+    let hashes = token_ids.par_iter().map(|&token_id| {
+        let metadata_id = PuzzleCard::from_token_id(token_id).ok()?.metadata_id();
+        fs::read(format!("{}/{}.json", config.metadata_directory, metadata_id)).ok().map(|bytes| fnv1a(&bytes))
+    }).inspect(|_| progress.tick()).collect::<Vec<_>>();
+
+    token_ids.into_iter().zip(hashes).filter_map(|(token_id, hash)| Some((token_id, hash?))).collect()
+}
+
+// Next.js writes the exported site's assets to _next/static/<build_id>/.
+fn site_build_id(site_directory: &str) -> Option<String> {
+    let static_directory = Path::new(site_directory).join("_next/static");
+
+    fs::read_dir(static_directory).ok()?
+        .filter_map(Result::ok)
+        .find(|entry| entry.path().join("_buildManifest.js").is_file())
+        .and_then(|entry| entry.file_name().into_string().ok())
+}
+
+// Only the settings that change how an image looks.
+fn settings_hash(config: &Config) -> u64 {
+    let mut settings = format!("{}x{}@{} {}x{} q{} {} {}{}",
+        config.capture_width, config.capture_height, config.device_scale_factor,
+        config.output_width, config.output_height, config.jpeg_quality,
+        config.card_selector, config.url_template,
+        if config.transparent { " transparent" } else { "" });
+
+    // Adding or changing a rendition re-captures every card so that each one has it.
+    // The directory isn't included because sharding changes it.
+    for r in &config.renditions {
+        settings.push_str(&format!(" {}x{} {:?} q{}", r.width, r.height, r.format, r.quality));
+    }
+
+    fnv1a(settings.as_bytes())
+}
+
+// Unlike DefaultHasher, this gives the same hashes across Rust versions.
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, &byte| (hash ^ byte as u64).wrapping_mul(0x100000001b3))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{config::{Rendition, RenditionFormat}, fake_capturer::test_config, shard::Shard};
+    use super::*;
+
+    #[test]
+    fn sharding_keeps_the_settings_hash() {
+        let rendition = Rendition { directory: "thumbnails".to_string(), width: 35, height: 35, format: RenditionFormat::Png, quality: 75 };
+        let config = Config { renditions: vec![rendition], ..test_config("settings_hash") };
+
+        assert_eq!(settings_hash(&Shard { index: 2, count: 4 }.config(&config)), settings_hash(&config));
+    }
+
+    fn manifest(recorded: Inputs, current: Inputs) -> Manifest {
+        Manifest {
+            path: String::new(),
+            recorded: BTreeMap::from([(1, recorded)]),
+            metadata_hashes: current.metadata_hash.map(|hash| (1, hash)).into_iter().collect(),
+            build_id: current.build_id,
+            settings_hash: current.settings_hash,
+            file: Mutex::new(None),
+        }
+    }
+
+    fn inputs(metadata_hash: Option<u64>, build_id: Option<&str>, settings_hash: u64) -> Inputs {
+        Inputs { metadata_hash, build_id: build_id.map(str::to_string), settings_hash }
+    }
+
+    #[test]
+    fn has_changed_compares_the_known_inputs() {
+        let captured = inputs(Some(1), Some("build"), 1);
+
+        assert_eq!(manifest(captured.clone(), captured.clone()).has_changed(1), Some(false));
+        assert_eq!(manifest(captured.clone(), captured.clone()).has_changed(2), None);
+
+        assert_eq!(manifest(captured.clone(), inputs(Some(2), Some("build"), 1)).has_changed(1), Some(true));
+        assert_eq!(manifest(captured.clone(), inputs(Some(1), Some("rebuild"), 1)).has_changed(1), Some(true));
+        assert_eq!(manifest(captured.clone(), inputs(Some(1), Some("build"), 2)).has_changed(1), Some(true));
+    }
+
+    #[test]
+    fn has_changed_ignores_unknown_inputs() {
+        let unknown = inputs(None, None, 1);
+        let known = inputs(Some(1), Some("build"), 1);
+
+        // Inputs that weren't available when the image was captured.
+        assert_eq!(manifest(unknown.clone(), known.clone()).has_changed(1), Some(false));
+
+        // Inputs that aren't available on this run.
+        assert_eq!(manifest(known, unknown).has_changed(1), Some(false));
+    }
+}
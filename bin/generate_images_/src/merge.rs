@@ -0,0 +1,187 @@
+use std::{collections::{BTreeMap, BTreeSet}, fs, path::Path};
+use rayon::prelude::*;
+use crate::{config::Config, output::{self, token_ids_from_output_directory}, print_examples, progress::Progress, shard::Shard};
+
+// Moves each shard's images into the output directory, but only once every
+// card is in exactly one shard, then combines the shards' manifests. Cards
+// already in the output directory count as merged so that a merge that was
+// interrupted can be re-run.
+pub fn merge_shards(config: &Config, count: u32, expected_token_ids: &BTreeSet<u128>) -> Result<(), String> {
+    if count == 0 { return Err("--shards must be at least 1".to_string()); }
+
+    let shards = (1..=count).map(|index| Shard { index, count }).collect::<Vec<_>>();
+    let mut shards_by_token_id = BTreeMap::<u128, Vec<u32>>::new();
+    let mut misplaced_token_ids = vec![];
+    let mut absent_directories = vec![];
+
+    for shard in &shards {
+        let shard_config = shard.config(config);
+
+        // A previous merge removes each shard's directory once its images are moved.
+        if !Path::new(&shard_config.output_directory).is_dir() {
+            absent_directories.push(shard_config.output_directory);
+            continue;
+        }
+
+        output::remove_partial_files(&shard_config);
+
+        for token_id in token_ids_from_output_directory(&shard_config) {
+            shards_by_token_id.entry(token_id).or_default().push(shard.index);
+            if !shard.contains(token_id) { misplaced_token_ids.push(token_id); }
+        }
+    }
+
+    fs::create_dir_all(&config.output_directory).unwrap();
+    let merged_token_ids = token_ids_from_output_directory(config);
+
+    let missing_token_ids = expected_token_ids.iter().copied().filter(|t| !shards_by_token_id.contains_key(t) && !merged_token_ids.contains(t)).collect::<Vec<_>>();
+    let duplicated_token_ids = shards_by_token_id.iter().filter(|(_, s)| s.len() > 1).map(|(&t, _)| t).collect::<Vec<_>>();
+    let surplus_token_ids = shards_by_token_id.keys().copied().filter(|t| !expected_token_ids.contains(t)).collect::<Vec<_>>();
+
+    print_examples("are missing from every shard", &missing_token_ids);
+    print_examples("are in more than one shard", &duplicated_token_ids);
+    print_examples("are in the wrong shard", &misplaced_token_ids);
+    print_examples("have no corresponding card", &surplus_token_ids);
+
+    if !(missing_token_ids.is_empty() && duplicated_token_ids.is_empty() && misplaced_token_ids.is_empty() && surplus_token_ids.is_empty()) {
+        let hint = if absent_directories.is_empty() { String::new() } else {
+            format!("\nThese shard directories don't exist: {}\nCopy each shard's output directory and manifest here first.", absent_directories.join(", "))
+        };
+
+        return Err(format!("Not merging because the shards don't contain every card exactly once.{}", hint));
+    }
+
+    // Written before anything is removed so that no shard's manifest is lost if
+    // the merge is interrupted. Later lines replace earlier ones so the manifests
+    // can simply be concatenated.
+    let mut manifest = fs::read_to_string(&config.manifest_path).unwrap_or_default();
+
+    for shard in &shards {
+        if let Ok(content) = fs::read_to_string(shard.config(config).manifest_path) { manifest.push_str(&content); }
+    }
+
+    output::write_atomically(&config.manifest_path, manifest.as_bytes(), |_| Ok(()))?;
+
+    for shard in &shards {
+        let shard_config = shard.config(config);
+
+        // The renditions' directories are merged in the same way as the main one.
+        for (from_output, to_output) in shard_config.outputs().iter().zip(config.outputs()) {
+            if !Path::new(&from_output.output_directory).is_dir() { continue; }
+
+            fs::create_dir_all(&to_output.output_directory).unwrap();
+            output::remove_partial_files(from_output);
+
+            let token_ids = token_ids_from_output_directory(from_output).into_iter().collect::<Vec<_>>();
+
+            let progress = Progress::new(&format!("Merged shard {}", shard), token_ids.len());
+
+            token_ids.par_iter().map(|&token_id| {
+                let (from, to) = (output::image_path(from_output, token_id), output::image_path(&to_output, token_id));
+                output::move_file(&from, &to).map_err(|e| format!("Could not move {} to {}: {}", from, to, e))
+            }).inspect(|_| progress.tick()).collect::<Result<Vec<_>, _>>()?;
+            let _ = fs::remove_dir(&from_output.output_directory);
+        }
+
+        let _ = fs::remove_file(&shard_config.manifest_path);
+    }
+
+    println!("\nMerged {} shards into {}", count, config.output_directory);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use puzzle_card::PuzzleCard;
+    use crate::{fake_capturer::test_config, output::image_path};
+    use super::*;
+
+    const NUM_CARDS: usize = 20;
+
+    fn token_ids() -> BTreeSet<u128> {
+        PuzzleCard::all().take(NUM_CARDS).map(|card| card.token_id()).collect()
+    }
+
+    fn manifest_line(token_id: u128) -> String {
+        format!("{}\t-\t-\t{:016x}\n", token_id, 1)
+    }
+
+    // Writes a placeholder image and manifest line for each card in its shard.
+    fn write_shards(config: &Config, shards: &[Shard]) {
+        for shard in shards {
+            let shard_config = shard.config(config);
+            fs::create_dir_all(&shard_config.output_directory).unwrap();
+
+            let token_ids = token_ids().into_iter().filter(|&t| shard.contains(t)).collect::<Vec<_>>();
+
+            for &token_id in &token_ids { fs::write(image_path(&shard_config, token_id), "image").unwrap(); }
+            fs::write(&shard_config.manifest_path, token_ids.iter().map(|&t| manifest_line(t)).collect::<String>()).unwrap();
+        }
+    }
+
+    fn shards(count: u32) -> Vec<Shard> {
+        (1..=count).map(|index| Shard { index, count }).collect()
+    }
+
+    #[test]
+    fn moves_every_shard_into_the_output_directory() {
+        let config = test_config("merge");
+        write_shards(&config, &shards(2));
+
+        merge_shards(&config, 2, &token_ids()).unwrap();
+
+        assert_eq!(token_ids_from_output_directory(&config), token_ids());
+
+        for shard in shards(2) {
+            assert!(!Path::new(&shard.config(&config).output_directory).exists());
+            assert!(!Path::new(&shard.config(&config).manifest_path).exists());
+        }
+
+        let manifest = fs::read_to_string(&config.manifest_path).unwrap();
+        assert!(token_ids().into_iter().all(|t| manifest.contains(&manifest_line(t))));
+    }
+
+    #[test]
+    fn refuses_to_merge_unless_every_card_is_in_one_shard() {
+        let config = test_config("merge_missing");
+        write_shards(&config, &shards(2));
+
+        let (first, second) = (shards(2)[0].config(&config), shards(2)[1].config(&config));
+        let token_id = token_ids_from_output_directory(&first).into_iter().next().unwrap();
+
+        fs::remove_file(image_path(&first, token_id)).unwrap();
+        assert!(merge_shards(&config, 2, &token_ids()).is_err());
+
+        fs::write(image_path(&first, token_id), "image").unwrap();
+        fs::write(image_path(&second, token_id), "image").unwrap();
+        assert!(merge_shards(&config, 2, &token_ids()).is_err());
+
+        // Nothing is moved or removed until the shards are complete.
+        assert!(token_ids_from_output_directory(&config).is_empty());
+        assert!(Path::new(&first.manifest_path).exists());
+    }
+
+    #[test]
+    fn finishes_a_merge_that_was_interrupted() {
+        let config = test_config("merge_interrupted");
+        write_shards(&config, &shards(2));
+
+        // As if the merge stopped after moving the first shard.
+        let first = shards(2)[0].config(&config);
+        fs::copy(&first.manifest_path, &config.manifest_path).unwrap();
+
+        for token_id in token_ids_from_output_directory(&first) {
+            output::move_file(&image_path(&first, token_id), &image_path(&config, token_id)).unwrap();
+        }
+
+        fs::remove_dir(&first.output_directory).unwrap();
+        fs::remove_file(&first.manifest_path).unwrap();
+
+        merge_shards(&config, 2, &token_ids()).unwrap();
+
+        let manifest = fs::read_to_string(&config.manifest_path).unwrap();
+
+        assert_eq!(token_ids_from_output_directory(&config), token_ids());
+        assert!(token_ids().into_iter().all(|t| manifest.contains(&manifest_line(t))));
+    }
+}
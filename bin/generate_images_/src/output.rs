@@ -0,0 +1,228 @@
+use std::{fs, io::{self, Write}, collections::BTreeSet, path::Path, time::SystemTime};
+use image::{io::Reader, DynamicImage, GenericImageView, ImageOutputFormat, jpeg::JpegEncoder};
+use puzzle_card::PuzzleCard;
+use ravif::{Img, RGBA8};
+use rayon::prelude::*;
+use crate::{config::{Config, RenditionFormat}, progress::Progress};
+
+// Images are written to a .partial file, read back and checked, then renamed
+// into place so that a crash mid-write never leaves a truncated image behind.
+
+const PARTIAL_EXTENSION: &str = ".partial";
+
+// From 1 (smallest files) to 10 (fastest). There are a million cards to encode.
+const AVIF_SPEED: u8 = 8;
+
+pub fn image_path(config: &Config, token_id: u128) -> String {
+    format!("{}/{}{}", config.output_directory, token_id, config.extension())
+}
+
+// Returns the size of the file.
+pub fn write_image(config: &Config, token_id: u128, image: &DynamicImage) -> Result<usize, String> {
+    let bytes = encode(config, image).map_err(|e| format!("Could not encode the image: {}", e))?;
+
+    write_atomically(&image_path(config, token_id), &bytes, |partial_path| check_image_file(partial_path, image.dimensions()))?;
+    Ok(bytes.len())
+}
+
+// Also used for the manifest. The .partial file is removed if check rejects it.
+pub fn write_atomically(path: &str, bytes: &[u8], check: impl FnOnce(&str) -> Result<(), String>) -> Result<(), String> {
+    let partial_path = format!("{}{}", path, PARTIAL_EXTENSION);
+
+    let write = || -> std::io::Result<()> {
+        let mut file = fs::File::create(&partial_path)?;
+        file.write_all(bytes)?;
+        file.sync_all()
+    };
+
+    write().map_err(|e| format!("Could not write {}: {}", partial_path, e))?;
+
+    if let Err(reason) = check(&partial_path) {
+        let _ = fs::remove_file(&partial_path);
+        return Err(reason);
+    }
+
+    fs::rename(&partial_path, path).map_err(|e| format!("Could not rename {} to {}: {}", partial_path, path, e))
+}
+
+fn encode(config: &Config, image: &DynamicImage) -> Result<Vec<u8>, String> {
+    let mut bytes = vec![];
+    let (width, height) = image.dimensions();
+
+    match config.format() {
+        RenditionFormat::Png => image.write_to(&mut bytes, ImageOutputFormat::Png).map_err(|e| e.to_string())?,
+        RenditionFormat::Jpeg => JpegEncoder::new_with_quality(&mut bytes, config.jpeg_quality).encode_image(image).map_err(|e| e.to_string())?,
+        RenditionFormat::Webp => bytes = webp::Encoder::from_rgba(&image.to_rgba8(), width, height).encode(config.jpeg_quality as f32).to_vec(),
+        RenditionFormat::Avif => {
+            let pixels = image.to_rgba8().pixels().map(|p| RGBA8::new(p[0], p[1], p[2], p[3])).collect::<Vec<_>>();
+            let encoder = ravif::Encoder::new().with_quality(config.jpeg_quality as f32).with_speed(AVIF_SPEED);
+
+            bytes = encoder.encode_rgba(Img::new(&pixels[..], width as usize, height as usize)).map_err(|e| e.to_string())?.avif_file;
+        },
+    }
+
+    Ok(bytes)
+}
+
+// The card's aspect ratio means only one dimension has to match the output size.
+pub fn fits_output_size(config: &Config, (width, height): (u32, u32)) -> bool {
+    let fits_within = width <= config.output_width && height <= config.output_height;
+    fits_within && (width == config.output_width || height == config.output_height)
+}
+
+fn check_image_file(path: &str, expected_dimensions: (u32, u32)) -> Result<(), String> {
+    let (width, height) = decoded_dimensions(path)?;
+
+    if (width, height) != expected_dimensions {
+        return Err(format!("{} is {}x{} but should be {}x{}", path, width, height, expected_dimensions.0, expected_dimensions.1));
+    }
+
+    Ok(())
+}
+
+// Decodes the image to check it isn't corrupt. Guesses the format from the
+// contents because .partial files have no image extension.
+pub fn decoded_dimensions(path: &str) -> Result<(u32, u32), String> {
+    let bytes = fs::read(path).map_err(|e| format!("{} could not be read: {}", path, e))?;
+
+    if bytes.get(8..12) == Some(b"WEBP") {
+        let image = webp::Decoder::new(&bytes).decode().ok_or_else(|| format!("{} could not be decoded", path))?;
+        return Ok((image.width(), image.height()));
+    }
+
+    if bytes.get(4..12) == Some(b"ftypavif") { return avif_dimensions(&bytes).ok_or_else(|| format!("{} has no image size", path)); }
+
+    Reader::new(io::Cursor::new(bytes)).with_guessed_format()
+        .map_err(|e| format!("{} could not be read: {}", path, e))?
+        .decode().map(|image| image.dimensions()).map_err(|e| format!("{} could not be decoded: {}", path, e))
+}
+
+// There's no AV1 decoder so AVIFs are only checked as far as the size in their
+// header, which is in an 'ispe' box: size, type, version and flags, width, height.
+fn avif_dimensions(bytes: &[u8]) -> Option<(u32, u32)> {
+    let ispe = bytes.windows(4).position(|window| window == b"ispe")?;
+    let field = |offset: usize| Some(u32::from_be_bytes(bytes.get(ispe + offset..ispe + offset + 4)?.try_into().ok()?));
+
+    Some((field(8)?, field(12)?))
+}
+
+// The directory might not exist yet on a dry run. Files with the extension
+// that aren't named by token ID are reported and otherwise left alone.
+pub fn token_ids_from_output_directory(config: &Config) -> BTreeSet<u128> {
+    let mut token_ids = BTreeSet::new();
+    let extension = config.extension();
+
+    if !Path::new(&config.output_directory).is_dir() { return token_ids; }
+
+    for result in fs::read_dir(&config.output_directory).unwrap() {
+        let dir_entry = result.unwrap();
+
+        let metadata = dir_entry.metadata().unwrap();
+        if !metadata.is_file() { continue; }
+
+        let file_name = match dir_entry.file_name().into_string() {
+            Ok(file_name) => file_name,
+            Err(file_name) => { eprintln!("Ignoring {:?} in {} because it is not a valid file name", file_name, config.output_directory); continue; }
+        };
+
+        let token_id_string = match file_name.strip_suffix(extension) { Some(s) => s, None => continue };
+
+        match token_id_string.parse::<u128>() {
+            Ok(token_id) => { token_ids.insert(token_id); },
+            Err(_) => eprintln!("Ignoring {} in {} because it isn't named by token ID", file_name, config.output_directory),
+        }
+    }
+
+    token_ids
+}
+
+// Left behind if the previous run was interrupted mid-write.
+pub fn remove_partial_files(config: &Config) {
+    for result in fs::read_dir(&config.output_directory).unwrap() {
+        let path = result.unwrap().path();
+
+        if path.to_string_lossy().ends_with(PARTIAL_EXTENSION) {
+            fs::remove_file(&path).unwrap();
+        }
+    }
+}
+
+// Decodes every existing image and returns those that are corrupt or the wrong size.
+pub fn scan_existing_images(config: &Config, token_ids: &[u128]) -> Vec<(u128, String)> {
+    let progress = Progress::new("Scanned", token_ids.len());
+
+    let results = token_ids.par_iter().map(|&token_id| {
+        let path = image_path(config, token_id);
+
+        decoded_dimensions(&path).and_then(|(width, height)| {
+            if fits_output_size(config, (width, height)) { return Ok(()); }
+            Err(format!("{} is {}x{} which doesn't fit {}x{}", path, width, height, config.output_width, config.output_height))
+        })
+    }).inspect(|_| progress.tick()).collect::<Vec<_>>();
+
+    token_ids.iter().zip(results).filter_map(|(&token_id, result)| result.err().map(|reason| (token_id, reason))).collect()
+}
+
+// Images with no corresponding card are moved aside rather than deleted in
+// case the cards or the metadata are what's wrong. Returns the directory.
+pub fn quarantine_images(config: &Config, token_ids: &[u128]) -> String {
+    let directory = format!("{}/{}", config.quarantine_directory, today());
+    fs::create_dir_all(&directory).unwrap();
+
+    for &token_id in token_ids {
+        let from = image_path(config, token_id);
+        let to = format!("{}/{}{}", directory, token_id, config.extension());
+
+        match move_file(&from, &to) {
+            Ok(()) => println!("Quarantined image of {}", PuzzleCard::describe(token_id)),
+            Err(error) => eprintln!("Could not move {} to {}: {}", from, to, error),
+        }
+    }
+
+    directory
+}
+
+// Renaming fails if the quarantine directory is on a different filesystem.
+pub fn move_file(from: &str, to: &str) -> io::Result<()> {
+    fs::rename(from, to).or_else(|_| {
+        fs::copy(from, to)?;
+        fs::remove_file(from)
+    })
+}
+
+// The UTC date as YYYY-MM-DD, converted from days since 1970 by Howard Hinnant's civil_from_days.
+fn today() -> String {
+    let seconds = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs() as i64;
+    let z = seconds.div_euclid(86400) + 719468;
+
+    let era = z.div_euclid(146097);
+    let day_of_era = z - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::fake_capturer::test_config;
+    use super::*;
+
+    #[test]
+    fn ignores_files_that_are_not_named_by_token_id() {
+        let config = test_config("stray_files");
+
+        for file_name in ["0", "notes", "-1"] {
+            fs::write(format!("{}/{}{}", config.output_directory, file_name, config.extension()), "image").unwrap();
+        }
+
+        fs::write(format!("{}{}", image_path(&config, 1), PARTIAL_EXTENSION), "image").unwrap();
+
+        assert_eq!(token_ids_from_output_directory(&config), BTreeSet::from([0]));
+    }
+}
@@ -0,0 +1,153 @@
+use std::{collections::BTreeMap, sync::{Arc, Mutex, MutexGuard, atomic::{AtomicBool, AtomicUsize, Ordering}}, thread, time::Duration};
+use crossbeam_queue::ArrayQueue;
+use puzzle_card::PuzzleCard;
+use crate::{manifest::Manifest, report::{CardRecord, Report, Status, Timings}, throughput::Throughput, watchdog::Watchdog};
+
+// How often the throughput of each stage and the ETA are printed.
+const REPORT_EVERY: usize = 100;
+
+// Screenshots that fail the checks are re-queued up to this many times.
+pub const MAX_CHECK_ATTEMPTS: usize = 3;
+
+// A card's tab is replaced up to this many times before giving up on it.
+pub const MAX_RESTARTS_PER_CARD: usize = 3;
+
+const IDLE_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// The queue of cards plus the bookkeeping shared by the tabs and the encoders.
+// A card is unfinished until it's saved or given up on because it might be
+// re-queued while other tabs are waiting for work.
+pub struct Pipeline {
+    queue: ArrayQueue<u128>,
+    num_unfinished: AtomicUsize,
+    num_captured: AtomicUsize,
+    num_total: usize,
+    stopped: AtomicBool,
+    manifest: Arc<Manifest>,
+    watchdog: Arc<Watchdog>,
+    rejections: Mutex<BTreeMap<u128, Vec<String>>>,
+    attempts: Mutex<BTreeMap<u128, usize>>,
+    report: Report,
+    pub throughput: Throughput,
+}
+
+impl Pipeline {
+    pub fn new(token_ids: &[u128], manifest: Arc<Manifest>, watchdog: Arc<Watchdog>, report: Report) -> Arc<Self> {
+        let queue = ArrayQueue::new(token_ids.len().max(1));
+        token_ids.iter().for_each(|t| queue.push(*t).unwrap());
+
+        Arc::new(Pipeline {
+            queue,
+            num_unfinished: AtomicUsize::new(token_ids.len()),
+            num_captured: AtomicUsize::new(0),
+            num_total: token_ids.len(),
+            stopped: AtomicBool::new(false),
+            manifest,
+            watchdog,
+            rejections: Mutex::new(BTreeMap::new()),
+            attempts: Mutex::new(BTreeMap::new()),
+            report,
+            throughput: Throughput::default(),
+        })
+    }
+
+    // Returns None once every card is finished or the run has stopped.
+    pub fn next_token_id(&self, slot: usize) -> Option<u128> {
+        self.watchdog.progress(slot, None);
+
+        loop {
+            if self.stopped.load(Ordering::SeqCst) { return None; }
+
+            if let Some(token_id) = self.queue.pop() {
+                *self.attempts.lock().unwrap().entry(token_id).or_default() += 1;
+                return Some(token_id);
+            }
+
+            if self.num_unfinished.load(Ordering::SeqCst) == 0 { return None; }
+
+            thread::sleep(IDLE_POLL_INTERVAL);
+        }
+    }
+
+    pub fn saved(&self, token_id: u128, timings: Timings, page_errors: Vec<String>, output_bytes: usize) {
+        self.manifest.record(token_id);
+
+        let previous = self.num_captured.fetch_add(1, Ordering::Relaxed);
+        println!("Captured {}/{}: {}", previous + 1, self.num_total, PuzzleCard::describe(token_id));
+
+        if (previous + 1).is_multiple_of(REPORT_EVERY) {
+            let num_remaining = self.num_unfinished.load(Ordering::SeqCst);
+            println!("{}, ETA {}", self.throughput, self.throughput.eta(self.num_total - num_remaining, num_remaining));
+        }
+
+        self.finish(token_id, Status::Saved, timings, page_errors, Some(output_bytes));
+    }
+
+    pub fn rejected(&self, token_id: u128, reason: String, timings: Timings, page_errors: Vec<String>) {
+        let mut rejections = self.rejections.lock().unwrap();
+        let reasons = rejections.entry(token_id).or_default();
+
+        println!("Screenshot of {} failed a check: {}", PuzzleCard::describe(token_id), reason);
+        reasons.push(reason);
+
+        let num_rejections = reasons.len();
+        drop(rejections);
+
+        if num_rejections < MAX_CHECK_ATTEMPTS { self.queue.push(token_id).unwrap(); } else { self.finish(token_id, Status::FailedChecks, timings, page_errors, None); }
+    }
+
+    // The watchdog has already recorded why it killed Chrome if killed is true.
+    // The page errors are from this attempt and are reported if it's the last.
+    pub fn stuck(&self, slot: usize, token_id: u128, reason: String, killed: bool, page_errors: Vec<String>) {
+        if !killed {
+            println!("{} is stuck on {}: {}", self.watchdog.describe_slot(slot), PuzzleCard::describe(token_id), reason);
+            self.watchdog.record_restart(slot, Some(token_id), reason);
+        }
+
+        let num_restarts = self.watchdog.restarts().iter().filter(|r| r.token_id == Some(token_id)).count();
+
+        if num_restarts < MAX_RESTARTS_PER_CARD {
+            self.queue.push(token_id).unwrap();
+        } else {
+            println!("Giving up on {} after {} restarts", PuzzleCard::describe(token_id), num_restarts);
+            self.finish(token_id, Status::GaveUp, Timings::default(), page_errors, None);
+        }
+    }
+
+    // Ends the run early, e.g. because a tab or encoder panicked and the cards it
+    // was working on will never be finished.
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+    }
+
+    pub fn num_unfinished(&self) -> usize {
+        self.num_unfinished.load(Ordering::SeqCst)
+    }
+
+    pub fn rejections(&self) -> MutexGuard<'_, BTreeMap<u128, Vec<String>>> {
+        self.rejections.lock().unwrap()
+    }
+
+    // Writes the summary to the report once every card is finished.
+    pub fn finish_report(&self) {
+        self.report.finish(&self.throughput);
+    }
+
+    // The timings and page errors are from the card's last attempt.
+    fn finish(&self, token_id: u128, status: Status, timings: Timings, page_errors: Vec<String>, output_bytes: Option<usize>) {
+        let rejections = self.rejections.lock().unwrap();
+        let restarts = self.watchdog.restarts().iter().filter(|r| r.token_id == Some(token_id)).map(|r| r.reason.clone()).collect();
+        let attempts = self.attempts.lock().unwrap().get(&token_id).copied().unwrap_or(0);
+
+        self.report.record(&CardRecord {
+            attempts,
+            restarts,
+            rejections: rejections.get(&token_id).map_or(&[], Vec::as_slice),
+            page_errors,
+            output_bytes,
+            ..CardRecord::new(token_id, status, timings)
+        });
+
+        self.num_unfinished.fetch_sub(1, Ordering::SeqCst);
+    }
+}
@@ -0,0 +1,20 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Prints how far through the output directory or metadata a parallel pass is,
+// e.g. token_ids.par_iter().map(..).inspect(|_| progress.tick()).collect()
+pub struct Progress {
+    description: String,
+    total: usize,
+    done: AtomicUsize,
+}
+
+impl Progress {
+    pub fn new(description: &str, total: usize) -> Self {
+        Progress { description: description.to_string(), total, done: AtomicUsize::new(0) }
+    }
+
+    pub fn tick(&self) {
+        let done = self.done.fetch_add(1, Ordering::Relaxed) + 1;
+        if done.is_multiple_of(10000) { println!("{} {}/{}", self.description, done, self.total); }
+    }
+}
@@ -0,0 +1,163 @@
+use std::{collections::HashMap, fs::File, io::{BufWriter, Write}, sync::{Mutex, atomic::{AtomicUsize, Ordering}}, time::Duration};
+use serde::Serialize;
+use puzzle_card::PuzzleCard;
+use crate::{config::exit_with_error, throughput::Throughput};
+
+// Writes a JSON line per card as it's saved or given up on so that a run can be
+// analysed afterwards, then a final line with the summary:
+//
+//   {"token_id":"256","card":"...","status":"saved","attempts":1,"restarts":[],...}
+//   {"summary":{"saved":1000,"failed_checks":2,"gave_up":1,...}}
+
+const NUM_SLOWEST_TYPES: usize = 5;
+
+// How long each stage of the last attempt at capturing a card took.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Timings {
+    pub navigation: Duration,
+    pub ready: Duration,
+    pub screenshot: Duration,
+    pub encode: Duration,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    Saved,
+    FailedChecks,
+    GaveUp,
+}
+
+#[derive(Serialize)]
+pub struct CardRecord<'a> {
+    pub token_id: String, // Token ids don't fit in a JavaScript number.
+    pub card: String,
+    pub card_type: &'static str,
+    pub status: Status,
+    pub attempts: usize,
+    pub restarts: Vec<String>,
+    pub rejections: &'a [String],
+    pub page_errors: Vec<String>, // Console errors, exceptions and failed requests.
+    pub navigation_ms: u128,
+    pub ready_ms: u128,
+    pub screenshot_ms: u128,
+    pub encode_ms: u128,
+    pub output_bytes: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct Summary {
+    saved: usize,
+    failed_checks: usize,
+    gave_up: usize,
+    with_page_errors: usize,
+    elapsed_seconds: u64,
+    cards_per_second: f64,
+    slowest_card_types: Vec<CardTypeTiming>,
+}
+
+#[derive(Serialize)]
+struct CardTypeTiming {
+    card_type: &'static str,
+    average_ms: u128, // Per saved card.
+}
+
+pub struct Report {
+    file: Mutex<BufWriter<File>>,
+    statuses: Mutex<HashMap<Status, usize>>,
+    num_with_page_errors: AtomicUsize,
+    millis_by_type: Mutex<HashMap<&'static str, (u128, u128)>>,
+}
+
+impl CardRecord<'_> {
+    pub fn new(token_id: u128, status: Status, timings: Timings) -> Self {
+        CardRecord {
+            token_id: token_id.to_string(),
+            card: PuzzleCard::describe(token_id),
+            card_type: PuzzleCard::from_token_id(token_id).unwrap().card_type.name(),
+            status,
+            attempts: 0,
+            restarts: vec![],
+            rejections: &[],
+            page_errors: vec![],
+            navigation_ms: timings.navigation.as_millis(),
+            ready_ms: timings.ready.as_millis(),
+            screenshot_ms: timings.screenshot.as_millis(),
+            encode_ms: timings.encode.as_millis(),
+            output_bytes: None,
+        }
+    }
+
+    fn total_millis(&self) -> u128 {
+        self.navigation_ms + self.ready_ms + self.screenshot_ms + self.encode_ms
+    }
+}
+
+impl Report {
+    pub fn create(path: &str) -> Self {
+        let file = File::create(path).unwrap_or_else(|e| exit_with_error(&format!("Could not create {}: {}", path, e)));
+
+        Report { file: Mutex::new(BufWriter::new(file)), statuses: Mutex::default(), num_with_page_errors: AtomicUsize::new(0), millis_by_type: Mutex::default() }
+    }
+
+    pub fn record(&self, record: &CardRecord) {
+        *self.statuses.lock().unwrap().entry(record.status).or_default() += 1;
+        if !record.page_errors.is_empty() { self.num_with_page_errors.fetch_add(1, Ordering::Relaxed); }
+
+        if record.status == Status::Saved {
+            let mut millis_by_type = self.millis_by_type.lock().unwrap();
+            let (sum, count) = millis_by_type.entry(record.card_type).or_default();
+
+            *sum += record.total_millis();
+            *count += 1;
+        }
+
+        self.write_line(record);
+    }
+
+    // Prints the slowest card types and writes the summary line.
+    pub fn finish(&self, throughput: &Throughput) {
+        let slowest_card_types = self.slowest_card_types();
+        let count = |status| self.statuses.lock().unwrap().get(&status).copied().unwrap_or(0);
+
+        if !slowest_card_types.is_empty() {
+            let types = slowest_card_types.iter().map(|t| format!("{} {:.1}s", t.card_type, t.average_ms as f64 / 1000.)).collect::<Vec<_>>();
+            println!("Slowest card types: {}", types.join(", "));
+        }
+
+        let saved = count(Status::Saved);
+        let elapsed = throughput.elapsed();
+        let with_page_errors = self.num_with_page_errors.load(Ordering::Relaxed);
+
+        if with_page_errors > 0 { println!("{} cards logged page errors. Their report entries list them.", with_page_errors); }
+
+        self.write_line(&HashMap::from([("summary", Summary {
+            saved,
+            failed_checks: count(Status::FailedChecks),
+            gave_up: count(Status::GaveUp),
+            with_page_errors,
+            elapsed_seconds: elapsed.as_secs(),
+            cards_per_second: saved as f64 / elapsed.as_secs_f64().max(1.),
+            slowest_card_types,
+        })]));
+
+        self.file.lock().unwrap().flush().unwrap();
+    }
+
+    fn slowest_card_types(&self) -> Vec<CardTypeTiming> {
+        let millis_by_type = self.millis_by_type.lock().unwrap();
+
+        let mut averages = millis_by_type.iter().map(|(&card_type, (sum, count))| CardTypeTiming { card_type, average_ms: sum / count }).collect::<Vec<_>>();
+        averages.sort_by_key(|t| std::cmp::Reverse(t.average_ms));
+        averages.truncate(NUM_SLOWEST_TYPES);
+
+        averages
+    }
+
+    fn write_line(&self, value: &impl Serialize) {
+        let mut file = self.file.lock().unwrap();
+
+        serde_json::to_writer(&mut *file, value).unwrap();
+        file.write_all(b"\n").unwrap();
+    }
+}
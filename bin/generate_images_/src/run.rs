@@ -0,0 +1,417 @@
+use std::{sync::{Arc, Mutex, mpsc::{self, SyncSender}}, thread, time::Instant};
+use rayon::ThreadPoolBuilder;
+use crate::{capture::{CardCapturer, Screenshot}, checks::LuminanceNorms, config::Config, encode::encode_screenshot, manifest::Manifest, pipeline::Pipeline, report::Report, watchdog::Watchdog};
+
+// A tab stops after failing to open this many times in a row, leaving its
+// instance's cards to the other tabs.
+const MAX_TAB_ATTEMPTS: usize = 3;
+
+// Stops the run if a tab or encoder panics. Otherwise the other tabs would wait
+// forever for the card it was working on to be finished.
+struct StopOnPanic<'a>(&'a Pipeline);
+
+impl Drop for StopOnPanic<'_> {
+    fn drop(&mut self) {
+        if thread::panicking() { self.0.stop(); }
+    }
+}
+
+// Runs num_threads capturers with tabs_per_instance tabs each until every card
+// is saved or given up on. The capturers are launched by calling launch(i).
+pub fn capture_cards<C, F>(config: &Arc<Config>, token_ids: &[u128], manifest: &Arc<Manifest>, launch: F) -> (Arc<Pipeline>, Arc<Watchdog>)
+    where C: CardCapturer + 'static, F: Fn(usize) -> Arc<C> + Send + Sync + 'static
+{
+    let num_instances = config.num_threads as usize;
+    let tabs_per_instance = config.tabs_per_instance as usize;
+
+    let watchdog = Watchdog::new(num_instances, tabs_per_instance, config.stall_timeout());
+    let watchdog_thread = watchdog.spawn();
+
+    let pipeline = Pipeline::new(token_ids, Arc::clone(manifest), Arc::clone(&watchdog), Report::create(&config.report_path));
+    let norms = Arc::new(LuminanceNorms::default());
+
+    // Tabs send screenshots to the encoders so they can load the next card straight
+    // away. The channel is bounded so tabs wait if the encoders fall behind.
+    let (sender, receiver) = mpsc::sync_channel::<Screenshot>(config.encode_queue_size);
+    let encode_pool = ThreadPoolBuilder::new().num_threads(config.encode_threads).build().unwrap();
+
+    // Each encoder takes the next screenshot as soon as it's free. par_bridge
+    // would wait for a batch of screenshots which might never arrive.
+    let receiver = Mutex::new(receiver);
+
+    let encoders = {
+        let (config, norms, pipeline) = (Arc::clone(config), Arc::clone(&norms), Arc::clone(&pipeline));
+
+        thread::spawn(move || encode_pool.scope(|scope| {
+            for _ in 0..encode_pool.current_num_threads() {
+                scope.spawn(|_| loop {
+                    let _stop_on_panic = StopOnPanic(&pipeline);
+
+                    // Bound separately so the lock is released before encoding.
+                    let received = receiver.lock().unwrap().recv();
+                    let Ok(screenshot) = received else { break };
+                    let (token_id, mut timings, page_errors) = (screenshot.token_id, screenshot.timings, screenshot.page_errors.clone());
+
+                    let started = Instant::now();
+                    let result = pipeline.throughput.encode.time(|| encode_screenshot(&config, &norms, screenshot));
+                    timings.encode = started.elapsed();
+
+                    match result {
+                        Ok(output_bytes) => pipeline.saved(token_id, timings, page_errors, output_bytes),
+                        Err(reason) => pipeline.rejected(token_id, reason, timings, page_errors),
+                    }
+                });
+            }
+        }))
+    };
+
+    let launch = Arc::new(launch);
+
+    let instances = (0..num_instances).map(|i| {
+        let (launch, watchdog, pipeline, sender) = (Arc::clone(&launch), Arc::clone(&watchdog), Arc::clone(&pipeline), sender.clone());
+
+        thread::spawn(move || {
+            let capturer = launch(i);
+
+            watchdog.register(i, {
+                let capturer = Arc::clone(&capturer);
+                move |generation| capturer.kill(generation)
+            });
+
+            let tabs = (0..tabs_per_instance).map(|t| {
+                let (capturer, watchdog, pipeline, sender) = (Arc::clone(&capturer), Arc::clone(&watchdog), Arc::clone(&pipeline), sender.clone());
+                thread::spawn(move || {
+                    let _stop_on_panic = StopOnPanic(&pipeline);
+                    run_tab(&*capturer, &watchdog, &pipeline, &sender, i * tabs_per_instance + t)
+                })
+            }).collect::<Vec<_>>();
+
+            // The panic has already been printed and the run stopped.
+            tabs.into_iter().for_each(|t| { let _ = t.join(); });
+        })
+    }).collect::<Vec<_>>();
+
+    // The encoders stop once every tab has dropped its sender.
+    drop(sender);
+
+    instances.into_iter().for_each(|t| t.join().unwrap());
+    let _ = encoders.join();
+
+    watchdog.stop();
+    watchdog_thread.join().unwrap();
+    pipeline.finish_report();
+
+    (pipeline, watchdog)
+}
+
+// Captures cards until there are none left, replacing the tab when it gets stuck.
+fn run_tab<C: CardCapturer>(capturer: &C, watchdog: &Watchdog, pipeline: &Pipeline, sender: &SyncSender<Screenshot>, slot: usize) {
+    let Some((mut tab, mut generation)) = open_tab(capturer, watchdog, slot) else { return };
+
+    while let Some(token_id) = pipeline.next_token_id(slot) {
+        let reason = match pipeline.throughput.capture.time(|| capturer.capture_card(&tab, watchdog, slot, token_id)) {
+            Ok(screenshot) => {
+                // The encoders have only gone if one of them panicked.
+                if pipeline.throughput.waiting_for_encoders.time(|| sender.send(screenshot).is_err()) { pipeline.stop(); return; }
+                continue;
+            },
+            Err(reason) => reason,
+        };
+
+        let killed = watchdog.was_killed(slot);
+        pipeline.stuck(slot, token_id, reason, killed, capturer.take_page_errors(&tab));
+
+        // A new tab is usually enough unless the watchdog killed Chrome.
+        if killed || capturer.close_tab(&tab).is_err() {
+            println!("Chrome instance {} is stuck, restarting...", capturer.index());
+            restart(capturer, generation);
+        }
+
+        let Some(opened) = open_tab(capturer, watchdog, slot) else { return };
+        (tab, generation) = opened;
+    }
+}
+
+// Returns None if the tab can't be opened even after restarting Chrome.
+fn open_tab<C: CardCapturer>(capturer: &C, watchdog: &Watchdog, slot: usize) -> Option<(C::Tab, usize)> {
+    for _ in 0..MAX_TAB_ATTEMPTS {
+        let generation = capturer.generation();
+
+        match capturer.new_tab() {
+            Ok((tab, generation)) => { watchdog.set_chrome(slot, generation); return Some((tab, generation)); },
+            Err(reason) => { println!("Chrome instance {} is stuck, restarting: {}", capturer.index(), reason); restart(capturer, generation); },
+        }
+    }
+
+    println!("{} is stopping because Chrome instance {} won't restart.", watchdog.describe_slot(slot), capturer.index());
+    None
+}
+
+fn restart<C: CardCapturer>(capturer: &C, generation: usize) {
+    if let Err(reason) = capturer.restart(generation) { println!("Could not restart Chrome instance {}: {}", capturer.index(), reason); }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeSet, fs, path::Path, time::Duration};
+    use puzzle_card::PuzzleCard;
+    use crate::{config::{Rendition, RenditionFormat}, fake_capturer::{Behaviour::*, FakeCapturer, PAGE_ERROR, test_config}, output::{decoded_dimensions, image_path}, pipeline::{MAX_CHECK_ATTEMPTS, MAX_RESTARTS_PER_CARD}};
+    use super::*;
+
+    fn token_ids(n: usize) -> Vec<u128> {
+        PuzzleCard::all().take(n).map(|card| card.token_id()).collect()
+    }
+
+    fn run(config: Config, fake: &Arc<FakeCapturer>, token_ids: &[u128]) -> (Arc<Config>, Arc<Pipeline>, Arc<Watchdog>) {
+        let config = Arc::new(config);
+        let manifest = Arc::new(Manifest::load(&config, &token_ids.iter().copied().collect::<BTreeSet<_>>()));
+
+        let fake = Arc::clone(fake);
+        let (pipeline, watchdog) = capture_cards(&config, token_ids, &manifest, move |_| Arc::clone(&fake));
+
+        (config, pipeline, watchdog)
+    }
+
+    fn is_saved(config: &Config, token_id: u128) -> bool {
+        Path::new(&image_path(config, token_id)).is_file()
+    }
+
+    fn report_lines(config: &Config) -> Vec<serde_json::Value> {
+        fs::read_to_string(&config.report_path).unwrap().lines().map(|line| serde_json::from_str(line).unwrap()).collect()
+    }
+
+    fn restart_reasons(watchdog: &Watchdog, token_id: u128) -> Vec<String> {
+        watchdog.restarts().iter().filter(|r| r.token_id == Some(token_id)).map(|r| r.reason.clone()).collect()
+    }
+
+    #[test]
+    fn saves_and_records_every_card() {
+        let fake = Arc::new(FakeCapturer::default());
+        let token_ids = token_ids(6);
+
+        let (config, pipeline, watchdog) = run(test_config("saves"), &fake, &token_ids);
+
+        assert!(token_ids.iter().all(|&t| is_saved(&config, t)));
+        assert!(pipeline.rejections().is_empty());
+        assert!(watchdog.restarts().is_empty());
+
+        let manifest = fs::read_to_string(&config.manifest_path).unwrap();
+        assert_eq!(manifest.lines().count(), token_ids.len());
+        assert_eq!(fake.num_tabs_opened(), config.tabs_per_instance as usize);
+
+        let report = report_lines(&config);
+        assert_eq!(report.len(), token_ids.len() + 1);
+        assert!(report[..token_ids.len()].iter().all(|line| line["status"] == "saved" && line["attempts"] == 1 && line["output_bytes"].as_u64() > Some(0)));
+        assert_eq!(report[token_ids.len()]["summary"]["saved"], token_ids.len());
+    }
+
+    #[test]
+    fn retries_screenshots_that_fail_the_checks() {
+        let fake = Arc::new(FakeCapturer::default());
+        let token_ids = token_ids(3);
+
+        fake.script(token_ids[0], &[Blank]);
+        fake.script(token_ids[1], &[Blank; MAX_CHECK_ATTEMPTS]);
+
+        let (config, pipeline, _) = run(test_config("rejects"), &fake, &token_ids);
+
+        assert!(is_saved(&config, token_ids[0]));
+        assert!(!is_saved(&config, token_ids[1]));
+        assert!(is_saved(&config, token_ids[2]));
+
+        let rejections = pipeline.rejections();
+        assert_eq!(rejections[&token_ids[0]].len(), 1);
+        assert_eq!(rejections[&token_ids[1]].len(), MAX_CHECK_ATTEMPTS);
+        assert!(rejections[&token_ids[1]][0].contains("single color"));
+    }
+
+    #[test]
+    fn reports_page_errors_and_optionally_retries_them() {
+        let fake = Arc::new(FakeCapturer::default());
+        let token_ids = token_ids(2);
+
+        fake.script(token_ids[0], &[PageError]);
+        let (config, pipeline, _) = run(test_config("page_errors"), &fake, &token_ids);
+
+        assert!(is_saved(&config, token_ids[0]));
+        assert!(pipeline.rejections().is_empty());
+        assert_eq!(report_lines(&config)[..2].iter().filter(|line| line["page_errors"][0] == PAGE_ERROR).count(), 1);
+
+        let fake = Arc::new(FakeCapturer::default());
+        fake.script(token_ids[0], &[PageError]);
+
+        let config = Config { fail_on_page_errors: true, ..test_config("fail_on_page_errors") };
+        let (config, pipeline, _) = run(config, &fake, &token_ids);
+
+        assert!(is_saved(&config, token_ids[0]));
+        assert_eq!(fake.attempts(token_ids[0]), 2);
+        assert!(pipeline.rejections()[&token_ids[0]][0].contains(PAGE_ERROR));
+    }
+
+    #[test]
+    fn keeps_the_alpha_channel_of_transparent_cards() {
+        let fake = Arc::new(FakeCapturer::default());
+        let token_ids = token_ids(2);
+
+        let config = Config { transparent: true, ..test_config("transparent") };
+        let (config, pipeline, _) = run(config, &fake, &token_ids);
+
+        assert_eq!(pipeline.rejections()[&token_ids[0]][0], "The corners of the card aren't transparent");
+        assert!(!is_saved(&config, token_ids[0]));
+
+        let fake = Arc::new(FakeCapturer::default());
+        fake.set_transparent(true);
+
+        let (config, _, _) = run(Config { transparent: true, ..test_config("transparent_corners") }, &fake, &token_ids);
+        let path = image_path(&config, token_ids[0]);
+        let image = image::open(&path).unwrap().into_rgba8();
+
+        assert!(path.ends_with(".png"));
+        assert_eq!(image.get_pixel(0, 0)[3], 0);
+        assert_eq!(image.get_pixel(image.width() / 2, image.height() / 2)[3], 255);
+    }
+
+    #[test]
+    fn writes_each_rendition_from_the_same_capture() {
+        let fake = Arc::new(FakeCapturer::default());
+        let token_ids = token_ids(2);
+
+        let config = test_config("renditions");
+
+        let renditions = [RenditionFormat::Png, RenditionFormat::Webp, RenditionFormat::Avif].map(|format| {
+            let directory = format!("{}_{:?}", config.output_directory, format);
+            fs::create_dir_all(&directory).unwrap();
+
+            Rendition { directory, width: 35, height: 35, format, quality: 75 }
+        });
+
+        let (config, _, _) = run(Config { renditions: renditions.to_vec(), ..config }, &fake, &token_ids);
+
+        assert!(is_saved(&config, token_ids[0]));
+        assert_eq!(fake.attempts(token_ids[0]), 1);
+
+        for (rendition_config, extension) in config.rendition_configs().iter().zip([".png", ".webp", ".avif"]) {
+            let path = image_path(rendition_config, token_ids[0]);
+
+            assert!(path.ends_with(extension));
+            assert_eq!(decoded_dimensions(&path).unwrap(), (25, 35));
+        }
+    }
+
+    #[test]
+    fn replaces_the_tab_when_a_card_fails_to_load() {
+        let fake = Arc::new(FakeCapturer::default());
+        let token_ids = token_ids(3);
+
+        fake.script(token_ids[1], &[Fail]);
+
+        let (config, _, watchdog) = run(test_config("replaces_tab"), &fake, &token_ids);
+
+        assert!(token_ids.iter().all(|&t| is_saved(&config, t)));
+        assert_eq!(restart_reasons(&watchdog, token_ids[1]), ["Navigation failed: Connection closed"]);
+
+        assert_eq!(fake.num_tabs_closed(), 1);
+        assert_eq!(fake.num_tabs_opened(), config.tabs_per_instance as usize + 1);
+        assert_eq!(fake.generation(), 0);
+    }
+
+    #[test]
+    fn restarts_chrome_when_the_tab_cannot_be_closed() {
+        let fake = Arc::new(FakeCapturer::default());
+        let token_ids = token_ids(3);
+
+        fake.script(token_ids[0], &[Fail]);
+        fake.set_close_fails(true);
+
+        let (config, _, watchdog) = run(test_config("restarts_chrome"), &fake, &token_ids);
+
+        assert!(token_ids.iter().all(|&t| is_saved(&config, t)));
+        assert_eq!(restart_reasons(&watchdog, token_ids[0]).len(), 1);
+        assert_eq!(fake.generation(), 1);
+    }
+
+    #[test]
+    fn stops_the_tabs_when_chrome_cannot_be_restarted() {
+        let fake = Arc::new(FakeCapturer::default());
+        let token_ids = token_ids(4);
+
+        fake.script(token_ids[0], &[Fail]);
+        fake.set_close_fails(true);
+        fake.set_restart_fails(true);
+
+        let (config, pipeline, _) = run(test_config("restart_fails"), &fake, &token_ids);
+
+        assert!(!is_saved(&config, token_ids[0]));
+        assert!(pipeline.num_unfinished() > 0);
+    }
+
+    #[test]
+    fn stops_the_run_when_a_tab_panics() {
+        let fake = Arc::new(FakeCapturer::default());
+        let token_ids = token_ids(4);
+
+        fake.script(token_ids[0], &[Panic]);
+
+        let (config, pipeline, _) = run(test_config("panics"), &fake, &token_ids);
+
+        assert!(!is_saved(&config, token_ids[0]));
+        assert!(pipeline.num_unfinished() > 0);
+    }
+
+    #[test]
+    fn retries_cards_that_overrun_their_deadline() {
+        let fake = Arc::new(FakeCapturer::default());
+        let token_ids = token_ids(2);
+
+        fake.script(token_ids[0], &[Hang(Duration::from_millis(1100))]);
+
+        let (config, _, watchdog) = run(test_config("overruns"), &fake, &token_ids);
+
+        assert!(token_ids.iter().all(|&t| is_saved(&config, t)));
+        assert_eq!(restart_reasons(&watchdog, token_ids[0]), ["Navigation took longer than 1s"]);
+        assert_eq!(fake.attempts(token_ids[0]), 2);
+    }
+
+    #[test]
+    fn kills_chrome_when_a_tab_makes_no_progress() {
+        let fake = Arc::new(FakeCapturer::default());
+        let token_ids = token_ids(2);
+
+        fake.script(token_ids[0], &[Hang(Duration::from_secs(10))]);
+
+        let config = Config { stall_timeout: 1, tabs_per_instance: 1, ..test_config("stalls") };
+        let (config, _, watchdog) = run(config, &fake, &token_ids);
+
+        assert!(token_ids.iter().all(|&t| is_saved(&config, t)));
+        assert_eq!(restart_reasons(&watchdog, token_ids[0]), ["Killed by the watchdog after 1s without progress"]);
+
+        assert_eq!(fake.num_kills(), 1);
+        assert_eq!(fake.attempts(token_ids[0]), 2);
+        assert_eq!(fake.generation(), 1);
+    }
+
+    #[test]
+    fn gives_up_on_cards_that_keep_failing() {
+        let fake = Arc::new(FakeCapturer::default());
+        let token_ids = token_ids(3);
+
+        fake.script(token_ids[2], &[Fail; MAX_RESTARTS_PER_CARD + 1]);
+
+        let (config, _, watchdog) = run(test_config("gives_up"), &fake, &token_ids);
+
+        assert!(!is_saved(&config, token_ids[2]));
+        assert!(is_saved(&config, token_ids[0]) && is_saved(&config, token_ids[1]));
+
+        assert_eq!(restart_reasons(&watchdog, token_ids[2]).len(), MAX_RESTARTS_PER_CARD);
+        assert_eq!(fake.attempts(token_ids[2]), MAX_RESTARTS_PER_CARD);
+
+        let report = report_lines(&config);
+        let line = report.iter().find(|line| line["token_id"] == token_ids[2].to_string()).unwrap();
+
+        assert_eq!(line["status"], "gave_up");
+        assert_eq!(line["page_errors"][0], PAGE_ERROR);
+        assert_eq!(line["attempts"], MAX_RESTARTS_PER_CARD);
+        assert_eq!(line["restarts"].as_array().unwrap().len(), MAX_RESTARTS_PER_CARD);
+        assert_eq!(report.last().unwrap()["summary"]["gave_up"], 1);
+    }
+}
@@ -0,0 +1,225 @@
+use std::{fs::File, io::{Read, Seek, SeekFrom}, path::{Path, PathBuf}, sync::Arc, thread::{self, JoinHandle}};
+use tiny_http::{Header, Method, Request, Response, Server, StatusCode};
+
+// Serves the exported website (./bin/build_website writes it to .gh-pages) on
+// an ephemeral port so that a run doesn't need ./bin/serve_website_static.
+
+const NUM_THREADS: usize = 8;
+
+pub struct StaticServer {
+    server: Arc<Server>,
+    threads: Vec<JoinHandle<()>>,
+    pub origin: String,
+}
+
+impl StaticServer {
+    pub fn start(directory: &str) -> Result<Self, String> {
+        let root = Path::new(directory).canonicalize().map_err(|e| format!("Could not serve {}: {}", directory, e))?;
+
+        if !root.join("card.html").is_file() {
+            return Err(format!("{} does not contain card.html. Run ./bin/build_website first", directory));
+        }
+
+        let server = Arc::new(Server::http("127.0.0.1:0").map_err(|e| format!("Could not start the web server: {}", e))?);
+        let address = server.server_addr().to_ip().ok_or("The web server is not listening on an IP address")?;
+
+        let threads = (0..NUM_THREADS).map(|_| {
+            let server = Arc::clone(&server);
+            let root = root.clone();
+
+            thread::spawn(move || {
+                for request in server.incoming_requests() {
+                    respond(request, &root);
+                }
+            })
+        }).collect();
+
+        Ok(StaticServer { server, threads, origin: format!("http://{}", address) })
+    }
+
+    pub fn stop(mut self) {
+        // Each call only unblocks one of the threads waiting for a request.
+        self.threads.iter().for_each(|_| self.server.unblock());
+        self.threads.drain(..).for_each(|t| t.join().unwrap());
+    }
+}
+
+fn respond(request: Request, root: &Path) {
+    let response = match request.method() {
+        Method::Get | Method::Head => file_response(&request, root),
+        _ => Err(StatusCode(405)),
+    };
+
+    // Chrome sometimes closes connections to videos early so ignore write errors.
+    let _ = match response {
+        Ok(response) => request.respond(response),
+        Err(code) => request.respond(Response::empty(code)),
+    };
+}
+
+fn file_response(request: &Request, root: &Path) -> Result<Response<Box<dyn Read + Send>>, StatusCode> {
+    let path = resolve_path(root, request.url()).ok_or(StatusCode(404))?;
+    let mut file = File::open(&path).map_err(|_| StatusCode(404))?;
+    let length = file.metadata().map_err(|_| StatusCode(500))?.len();
+
+    let content_type = Header::from_bytes("Content-Type", content_type(&path)).unwrap();
+    let accept_ranges = Header::from_bytes("Accept-Ranges", "bytes").unwrap();
+
+    let range = request.headers().iter()
+        .find(|h| h.field.equiv("Range"))
+        .map(|h| parse_range(h.value.as_str(), length).ok_or(StatusCode(416)))
+        .transpose()?;
+
+    // Videos are requested in ranges when the page seeks to a start time.
+    let response = match range {
+        Some((start, end)) => {
+            file.seek(SeekFrom::Start(start)).map_err(|_| StatusCode(500))?;
+
+            let content_range = Header::from_bytes("Content-Range", format!("bytes {}-{}/{}", start, end, length)).unwrap();
+            let reader: Box<dyn Read + Send> = Box::new(file.take(end - start + 1));
+
+            Response::new(StatusCode(206), vec![content_type, accept_ranges, content_range], reader, Some((end - start + 1) as usize), None)
+        },
+        None => {
+            let reader: Box<dyn Read + Send> = Box::new(file);
+            Response::new(StatusCode(200), vec![content_type, accept_ranges], reader, Some(length as usize), None)
+        },
+    };
+
+    Ok(response)
+}
+
+// Maps e.g. /card?tokenID=1 to card.html in the same way as http-server does.
+fn resolve_path(root: &Path, url: &str) -> Option<PathBuf> {
+    let url_path = url.split(['?', '#']).next().unwrap();
+    let url_path = percent_decode(url_path)?;
+
+    let mut path = root.to_path_buf();
+
+    for segment in url_path.split('/').filter(|s| !s.is_empty()) {
+        if segment == ".." || segment.contains('\\') { return None; }
+        path.push(segment);
+    }
+
+    if path.is_dir() { path.push("index.html"); }
+    if path.is_file() { return Some(path); }
+
+    let mut with_extension = path.into_os_string();
+    with_extension.push(".html");
+
+    let with_extension = PathBuf::from(with_extension);
+    if with_extension.is_file() { Some(with_extension) } else { None }
+}
+
+fn percent_decode(string: &str) -> Option<String> {
+    let mut bytes = vec![];
+    let mut rest = string.as_bytes();
+
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte == b'%' {
+            let hex = std::str::from_utf8(tail.get(..2)?).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(byte);
+            rest = tail;
+        }
+    }
+
+    String::from_utf8(bytes).ok()
+}
+
+// Only single ranges such as 'bytes=100-', 'bytes=100-199' and 'bytes=-100' are supported.
+fn parse_range(value: &str, length: u64) -> Option<(u64, u64)> {
+    let (start, end) = value.trim().strip_prefix("bytes=")?.split_once('-')?;
+
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", suffix) => (length.checked_sub(suffix.parse().ok()?)?, length.checked_sub(1)?),
+        (start, "") => (start.parse().ok()?, length.checked_sub(1)?),
+        (start, end) => (start.parse().ok()?, end.parse::<u64>().ok()?.min(length.checked_sub(1)?)),
+    };
+
+    if start > end { None } else { Some((start, end)) }
+}
+
+fn content_type(path: &Path) -> &'static str {
+    match path.extension().and_then(|e| e.to_str()).unwrap_or("") {
+        "html" => "text/html; charset=utf-8",
+        "js" => "application/javascript; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "json" => "application/json",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "ico" => "image/x-icon",
+        "mov" | "mp4" => "video/mp4",
+        "webm" => "video/webm",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "ttf" => "font/ttf",
+        "otf" => "font/otf",
+        "txt" => "text/plain; charset=utf-8",
+        "xml" => "application/xml",
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, process};
+    use super::*;
+
+    // A site with a page, a directory index and a file outside of the root.
+    fn site(name: &str) -> PathBuf {
+        let directory = std::env::temp_dir().join(format!("generate_images_test_{}_{}", name, process::id()));
+        let root = directory.join("site");
+
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(root.join("images")).unwrap();
+
+        fs::write(root.join("card.html"), "card").unwrap();
+        fs::write(root.join("images/index.html"), "index").unwrap();
+        fs::write(directory.join("secret.txt"), "secret").unwrap();
+
+        root
+    }
+
+    #[test]
+    fn resolves_pages_like_http_server() {
+        let root = site("resolve_path");
+
+        assert_eq!(resolve_path(&root, "/card?tokenID=1"), Some(root.join("card.html")));
+        assert_eq!(resolve_path(&root, "/card.html#top"), Some(root.join("card.html")));
+        assert_eq!(resolve_path(&root, "/images/"), Some(root.join("images/index.html")));
+        assert_eq!(resolve_path(&root, "/%63ard"), Some(root.join("card.html")));
+        assert_eq!(resolve_path(&root, "/missing"), None);
+    }
+
+    #[test]
+    fn refuses_paths_outside_the_root() {
+        let root = site("traversal");
+
+        for url in ["/../secret.txt", "/images/../../secret.txt", "/%2e%2e/secret.txt", "/%2E%2E/secret.txt", "/..%5csecret.txt", "/%zz"] {
+            assert_eq!(resolve_path(&root, url), None, "{}", url);
+        }
+    }
+
+    #[test]
+    fn parses_single_byte_ranges() {
+        assert_eq!(parse_range("bytes=100-199", 1000), Some((100, 199)));
+        assert_eq!(parse_range("bytes=100-", 1000), Some((100, 999)));
+        assert_eq!(parse_range("bytes=-100", 1000), Some((900, 999)));
+        assert_eq!(parse_range("bytes=900-5000", 1000), Some((900, 999)));
+    }
+
+    #[test]
+    fn rejects_unsatisfiable_ranges() {
+        assert_eq!(parse_range("bytes=0-", 0), None);
+        assert_eq!(parse_range("bytes=-1", 0), None);
+        assert_eq!(parse_range("bytes=-0", 1000), None);
+        assert_eq!(parse_range("bytes=200-100", 1000), None);
+        assert_eq!(parse_range("bytes=0-1,5-6", 1000), None);
+        assert_eq!(parse_range("items=0-1", 1000), None);
+    }
+}
@@ -0,0 +1,57 @@
+use std::{fmt, str::FromStr};
+use crate::{config::{Config, Rendition, with_suffix}, manifest::fnv1a};
+
+// Splits the cards between machines by hashing their token ids so that every
+// machine agrees on which cards are theirs without coordinating. Each shard
+// writes to its own output directory and manifest which are merged afterwards.
+
+#[derive(Clone, Copy, Debug)]
+pub struct Shard {
+    pub index: u32, // From 1 to count.
+    pub count: u32,
+}
+
+impl Shard {
+    pub fn contains(&self, token_id: u128) -> bool {
+        fnv1a(&token_id.to_le_bytes()) % self.count as u64 == (self.index - 1) as u64
+    }
+
+    // E.g. card_images_shard_2_of_4 and card_images_manifest_shard_2_of_4.tsv
+    pub fn config(&self, config: &Config) -> Config {
+        let suffix = format!("_shard_{}_of_{}", self.index, self.count);
+
+        let renditions = config.renditions.iter().map(|rendition| Rendition {
+            directory: format!("{}{}", rendition.directory.trim_end_matches('/'), suffix),
+            ..rendition.clone()
+        }).collect();
+
+        Config {
+            output_directory: format!("{}{}", config.output_directory.trim_end_matches('/'), suffix),
+            renditions,
+            manifest_path: with_suffix(&config.manifest_path, &suffix),
+            report_path: with_suffix(&config.report_path, &suffix),
+            ..config.clone()
+        }
+    }
+}
+
+impl FromStr for Shard {
+    type Err = String;
+
+    fn from_str(string: &str) -> Result<Self, Self::Err> {
+        let (index, count) = string.split_once('/').ok_or_else(|| format!("Expected e.g. 2/4 but got '{}'", string))?;
+
+        let index = index.trim().parse::<u32>().map_err(|_| format!("'{}' is not a number", index))?;
+        let count = count.trim().parse::<u32>().map_err(|_| format!("'{}' is not a number", count))?;
+
+        if index == 0 || index > count { return Err(format!("The shard must be between 1 and {}", count)); }
+
+        Ok(Shard { index, count })
+    }
+}
+
+impl fmt::Display for Shard {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.index, self.count)
+    }
+}
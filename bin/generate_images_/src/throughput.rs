@@ -0,0 +1,74 @@
+use std::{fmt, sync::atomic::{AtomicU64, AtomicUsize, Ordering}, time::{Duration, Instant}};
+
+// Measures each stage so it's clear whether Chrome or the encoders are the
+// bottleneck. If tabs spend a lot of time waiting to send screenshots then
+// there aren't enough encode_threads for the number of tabs.
+
+#[derive(Default)]
+pub struct Stage {
+    count: AtomicUsize,
+    busy_micros: AtomicU64,
+}
+
+pub struct Throughput {
+    started: Instant,
+    pub capture: Stage,
+    pub encode: Stage,
+    pub waiting_for_encoders: Stage,
+}
+
+impl Stage {
+    pub fn time<T>(&self, f: impl FnOnce() -> T) -> T {
+        let started = Instant::now();
+        let result = f();
+
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.busy_micros.fetch_add(started.elapsed().as_micros() as u64, Ordering::Relaxed);
+
+        result
+    }
+
+    fn count(&self) -> usize {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    fn busy(&self) -> Duration {
+        Duration::from_micros(self.busy_micros.load(Ordering::Relaxed))
+    }
+
+    fn average_millis(&self) -> u128 {
+        self.busy().as_millis() / self.count().max(1) as u128
+    }
+}
+
+impl Throughput {
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    // Assumes the remaining cards take as long as the ones finished so far.
+    pub fn eta(&self, num_finished: usize, num_remaining: usize) -> String {
+        if num_finished == 0 { return "unknown".to_string(); }
+
+        let seconds = self.elapsed().as_secs() * num_remaining as u64 / num_finished as u64;
+        format!("{}h {:02}m", seconds / 3600, seconds / 60 % 60)
+    }
+}
+
+impl Default for Throughput {
+    fn default() -> Self {
+        Throughput { started: Instant::now(), capture: Stage::default(), encode: Stage::default(), waiting_for_encoders: Stage::default() }
+    }
+}
+
+// E.g. "Capture: 12.5/s (640ms each), encode: 12.1/s (210ms each), tabs waited 3s for encoders"
+impl fmt::Display for Throughput {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let seconds = self.started.elapsed().as_secs_f64().max(1.);
+        let rate = |stage: &Stage| stage.count() as f64 / seconds;
+
+        write!(f, "Capture: {:.1}/s ({}ms each), ", rate(&self.capture), self.capture.average_millis())?;
+        write!(f, "encode: {:.1}/s ({}ms each), ", rate(&self.encode), self.encode.average_millis())?;
+        write!(f, "tabs waited {}s for encoders", self.waiting_for_encoders.busy().as_secs())
+    }
+}
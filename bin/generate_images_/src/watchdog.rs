@@ -0,0 +1,136 @@
+use std::{sync::{Arc, Mutex, MutexGuard, atomic::{AtomicBool, Ordering}}, thread, time::{Duration, Instant}};
+use puzzle_card::PuzzleCard;
+
+// Chrome can hang inside a DevTools call so that a tab never reaches its next
+// deadline. The watchdog kills that tab's Chrome instance so the blocked calls
+// fail and its tabs restart it. Each tab has a slot numbered across instances.
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+// Kills a generation of an instance, e.g. by calling CardCapturer::kill.
+type Kill = Arc<dyn Fn(usize) + Send + Sync>;
+
+pub struct Watchdog {
+    slots: Vec<Mutex<SlotStatus>>,
+    instances: Vec<Mutex<Option<Kill>>>,
+    tabs_per_instance: usize,
+    stall_timeout: Duration,
+    restarts: Mutex<Vec<Restart>>,
+    finished: AtomicBool,
+}
+
+struct SlotStatus {
+    last_progress: Instant,
+    token_id: Option<u128>,
+    generation: Option<usize>, // Of the instance the tab was opened in.
+    killed: bool,
+}
+
+pub struct Restart {
+    pub slot: usize,
+    pub token_id: Option<u128>,
+    pub reason: String,
+}
+
+impl Watchdog {
+    pub fn new(num_instances: usize, tabs_per_instance: usize, stall_timeout: Duration) -> Arc<Self> {
+        let status = || Mutex::new(SlotStatus { last_progress: Instant::now(), token_id: None, generation: None, killed: false });
+
+        Arc::new(Watchdog {
+            slots: (0..num_instances * tabs_per_instance).map(|_| status()).collect(),
+            instances: (0..num_instances).map(|_| Mutex::new(None)).collect(),
+            tabs_per_instance,
+            stall_timeout,
+            restarts: Mutex::new(vec![]),
+            finished: AtomicBool::new(false),
+        })
+    }
+
+    pub fn spawn(self: &Arc<Self>) -> thread::JoinHandle<()> {
+        let watchdog = Arc::clone(self);
+
+        thread::spawn(move || {
+            while !watchdog.finished.load(Ordering::Relaxed) {
+                thread::sleep(CHECK_INTERVAL);
+                watchdog.kill_stalled_instances();
+            }
+        })
+    }
+
+    pub fn stop(&self) {
+        self.finished.store(true, Ordering::Relaxed);
+    }
+
+    // Tabs call this at the start of each step, or with None while they're idle.
+    pub fn progress(&self, slot: usize, token_id: Option<u128>) {
+        let mut status = self.slots[slot].lock().unwrap();
+
+        status.last_progress = Instant::now();
+        status.token_id = token_id;
+    }
+
+    // Called once each instance has launched.
+    pub fn register(&self, instance: usize, kill: impl Fn(usize) + Send + Sync + 'static) {
+        *self.instances[instance].lock().unwrap() = Some(Arc::new(kill));
+    }
+
+    pub fn set_chrome(&self, slot: usize, generation: usize) {
+        let mut status = self.slots[slot].lock().unwrap();
+
+        status.generation = Some(generation);
+        status.killed = false;
+        status.last_progress = Instant::now();
+    }
+
+    pub fn was_killed(&self, slot: usize) -> bool {
+        self.slots[slot].lock().unwrap().killed
+    }
+
+    pub fn describe_slot(&self, slot: usize) -> String {
+        format!("Tab {} of Chrome instance {}", slot % self.tabs_per_instance, slot / self.tabs_per_instance)
+    }
+
+    // Fails if the step errors or overruns its deadline, which restarts Chrome.
+    pub fn run_step<T, E: std::fmt::Display>(&self, slot: usize, token_id: u128, step: &str, deadline: Duration, f: impl FnOnce() -> Result<T, E>) -> Result<T, String> {
+        self.progress(slot, Some(token_id));
+
+        let started = Instant::now();
+        let result = f();
+
+        if started.elapsed() > deadline { return Err(format!("{} took longer than {}s", step, deadline.as_secs())); }
+        result.map_err(|e| format!("{} failed: {}", step, e))
+    }
+
+    pub fn record_restart(&self, slot: usize, token_id: Option<u128>, reason: String) {
+        self.restarts.lock().unwrap().push(Restart { slot, token_id, reason });
+    }
+
+    pub fn restarts(&self) -> MutexGuard<'_, Vec<Restart>> {
+        self.restarts.lock().unwrap()
+    }
+
+    fn kill_stalled_instances(&self) {
+        for slot in 0..self.slots.len() {
+            let status = self.slots[slot].lock().unwrap();
+            if status.killed || status.last_progress.elapsed() < self.stall_timeout { continue; }
+
+            // Idle tabs and instances that are still starting don't need to make progress.
+            let (generation, token_id) = match (status.generation, status.token_id) { (Some(generation), Some(token_id)) => (generation, token_id), _ => continue };
+            let instance = slot / self.tabs_per_instance;
+            drop(status);
+
+            println!("{} made no progress for {}s on {}, killing Chrome...", self.describe_slot(slot), self.stall_timeout.as_secs(), PuzzleCard::describe(token_id));
+            self.record_restart(slot, Some(token_id), format!("Killed by the watchdog after {}s without progress", self.stall_timeout.as_secs()));
+
+            // Marked first so that the tabs know why their calls fail. The other
+            // tabs in the instance will fail too.
+            for other in &self.slots[instance * self.tabs_per_instance..(instance + 1) * self.tabs_per_instance] {
+                let mut other = other.lock().unwrap();
+                if other.generation == Some(generation) { other.killed = true; }
+            }
+
+            let kill = self.instances[instance].lock().unwrap().clone();
+            if let Some(kill) = kill { kill(generation); }
+        }
+    }
+}
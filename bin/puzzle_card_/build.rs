@@ -0,0 +1,173 @@
+// Generates the card constants and enums from public/PuzzleCard.js so that they
+// can't drift from the arrays that ./bin/update_constants maintains. The build
+// fails if the arrays are inconsistent with each other.
+
+use std::{env, fs, path::Path};
+
+const PUZZLE_CARD_JS: &str = "../../public/PuzzleCard.js";
+
+const NAME_TABLES: [(&str, &str); 8] = [
+    ("Series", "SERIES_NAMES"),
+    ("Puzzle", "PUZZLE_NAMES"),
+    ("Tier", "TIER_NAMES"),
+    ("CardType", "TYPE_NAMES"),
+    ("Color", "COLOR_NAMES"),
+    ("Variant", "VARIANT_NAMES"),
+    ("Condition", "CONDITION_NAMES"),
+    ("Edition", "EDITION_NAMES"),
+];
+
+const NUMBER_TABLES: [&str; 6] = [
+    "NUM_PUZZLES_PER_SERIES",
+    "SERIES_FOR_EACH_PUZZLE",
+    "PUZZLE_OFFSET_PER_SERIES",
+    "NUM_COLOR_SLOTS_PER_TYPE",
+    "NUM_VARIANTS_PER_TYPE",
+    "VARIANT_OFFSET_PER_TYPE",
+];
+
+fn main() {
+    println!("cargo:rerun-if-changed={}", PUZZLE_CARD_JS);
+    println!("cargo:rerun-if-changed=build.rs");
+
+    let source = fs::read_to_string(PUZZLE_CARD_JS).unwrap();
+
+    let names = NAME_TABLES.map(|(_, constant)| parse_strings(&array_literal(&source, constant)));
+    let numbers = NUMBER_TABLES.map(|constant| parse_numbers(&array_literal(&source, constant)));
+
+    let errors = check_consistency(&names, &numbers);
+
+    if !errors.is_empty() {
+        panic!("\n\nThe constants in {} are inconsistent:\n  - {}\n", PUZZLE_CARD_JS, errors.join("\n  - "));
+    }
+
+    let mut code = String::new();
+
+    for ((_, constant), values) in NAME_TABLES.iter().zip(&names) {
+        let items = values.iter().map(|v| format!("{:?}", v)).collect::<Vec<_>>();
+        code += &format!("pub const {}: [&str; {}] = [{}];\n", constant, values.len(), items.join(", "));
+    }
+
+    for (constant, values) in NUMBER_TABLES.iter().zip(&numbers) {
+        let items = values.iter().map(|v| v.to_string()).collect::<Vec<_>>();
+        code += &format!("pub const {}: [usize; {}] = [{}];\n", constant, values.len(), items.join(", "));
+    }
+
+    for ((enum_name, constant), values) in NAME_TABLES.iter().zip(&names) {
+        let variants = values.iter().map(|v| identifier(v)).collect::<Vec<_>>();
+        code += &format!("named_enum!({}, {}, [{}]);\n", enum_name, constant, variants.join(", "));
+    }
+
+    let out_path = Path::new(&env::var("OUT_DIR").unwrap()).join("constants.rs");
+    fs::write(out_path, code).unwrap();
+}
+
+fn array_literal(source: &str, constant: &str) -> String {
+    let prefix = format!("PuzzleCard.{} = [", constant);
+
+    let line = source.lines().find(|l| l.starts_with(&prefix))
+        .unwrap_or_else(|| panic!("Could not find PuzzleCard.{} in {}", constant, PUZZLE_CARD_JS));
+
+    let end = line.rfind("];").unwrap_or_else(|| panic!("PuzzleCard.{} must be on one line", constant));
+    line[prefix.len()..end].to_string()
+}
+
+// The arrays are written by JSON.stringify so strings only use double quotes.
+fn parse_strings(literal: &str) -> Vec<String> {
+    let mut strings = vec![];
+    let mut chars = literal.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '"' { continue; }
+        let mut string = String::new();
+
+        loop {
+            match chars.next() {
+                Some('"') => break,
+                Some('\\') => string.push(chars.next().unwrap()),
+                Some(c) => string.push(c),
+                None => panic!("Unterminated string in [{}]", literal),
+            }
+        }
+
+        strings.push(string);
+    }
+
+    strings
+}
+
+fn parse_numbers(literal: &str) -> Vec<usize> {
+    literal.split(',').map(|s| s.trim().parse().unwrap_or_else(|_| panic!("Not a number: {}", s))).collect()
+}
+
+// E.g. "Crab’s Day Out" -> CrabsDayOut, "Walk Left 1" -> WalkLeft1
+fn identifier(name: &str) -> String {
+    name.replace('-', " ").split_whitespace().map(|word| {
+        let word = word.chars().filter(|c| c.is_ascii_alphanumeric()).collect::<String>();
+        let mut chars = word.chars();
+
+        match chars.next() {
+            Some(first) => first.to_ascii_uppercase().to_string() + chars.as_str(),
+            None => String::new(),
+        }
+    }).collect()
+}
+
+fn check_consistency(names: &[Vec<String>; 8], numbers: &[Vec<usize>; 6]) -> Vec<String> {
+    let [series, puzzles, _, types, _, variants, _, _] = names;
+    let [num_puzzles, series_for_puzzle, puzzle_offsets, num_color_slots, num_variants, variant_offsets] = numbers;
+
+    let mut errors = vec![];
+
+    for ((_, constant), values) in NAME_TABLES.iter().zip(names) {
+        if values.len() > 256 { errors.push(format!("{} has more than 256 names so can't fit in a byte", constant)); }
+
+        let mut identifiers = values.iter().map(|v| identifier(v)).collect::<Vec<_>>();
+        identifiers.sort();
+
+        for pair in identifiers.windows(2) {
+            if pair[0] == pair[1] { errors.push(format!("{} has more than one name that becomes {}", constant, pair[0])); }
+        }
+    }
+
+    let mut check_length = |constant: &str, values: &[usize], expected: usize, of: &str| {
+        if values.len() != expected {
+            errors.push(format!("{} has {} entries but {} has {}", constant, values.len(), of, expected));
+        }
+    };
+
+    check_length("NUM_PUZZLES_PER_SERIES", num_puzzles, series.len(), "SERIES_NAMES");
+    check_length("PUZZLE_OFFSET_PER_SERIES", puzzle_offsets, series.len(), "SERIES_NAMES");
+    check_length("SERIES_FOR_EACH_PUZZLE", series_for_puzzle, puzzles.len(), "PUZZLE_NAMES");
+    check_length("NUM_COLOR_SLOTS_PER_TYPE", num_color_slots, types.len(), "TYPE_NAMES");
+    check_length("NUM_VARIANTS_PER_TYPE", num_variants, types.len(), "TYPE_NAMES");
+    check_length("VARIANT_OFFSET_PER_TYPE", variant_offsets, types.len(), "TYPE_NAMES");
+
+    let total_puzzles = num_puzzles.iter().sum::<usize>();
+    if total_puzzles != puzzles.len() {
+        errors.push(format!("NUM_PUZZLES_PER_SERIES sums to {} but PUZZLE_NAMES has {}", total_puzzles, puzzles.len()));
+    }
+
+    let mut offset = 0;
+    for (i, &n) in num_puzzles.iter().enumerate() {
+        if puzzle_offsets.get(i).is_some_and(|&o| o != offset) {
+            errors.push(format!("PUZZLE_OFFSET_PER_SERIES[{}] is {} but NUM_PUZZLES_PER_SERIES implies {}", i, puzzle_offsets[i], offset));
+        }
+
+        for p in offset..offset + n {
+            if series_for_puzzle.get(p).is_some_and(|&s| s != i) {
+                errors.push(format!("SERIES_FOR_EACH_PUZZLE[{}] is {} but NUM_PUZZLES_PER_SERIES implies {}", p, series_for_puzzle[p], i));
+            }
+        }
+
+        offset += n;
+    }
+
+    for (i, (&offset, &n)) in variant_offsets.iter().zip(num_variants).enumerate() {
+        if offset + n.max(1) > variants.len() {
+            errors.push(format!("The variants for type {} run past the end of VARIANT_NAMES", i));
+        }
+    }
+
+    errors
+}
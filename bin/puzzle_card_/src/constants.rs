@@ -0,0 +1,4 @@
+// The tables and enums in this module are generated by build.rs from the arrays
+// in public/PuzzleCard.js. Run ./bin/update_constants to change them.
+
+include!(concat!(env!("OUT_DIR"), "/constants.rs"));
@@ -0,0 +1,84 @@
+use std::{collections::BTreeMap, fmt};
+use crate::*;
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CardCounts {
+    pub total: usize,
+    pub per_series: BTreeMap<Series, usize>,
+    pub per_tier: BTreeMap<Tier, usize>,
+}
+
+impl PuzzleCard {
+    // Yields every valid card in the same order as ./bin/generate_metadata writes
+    // them. The cards are generated as they're needed rather than all up front.
+    pub fn all() -> impl Iterator<Item = PuzzleCard> {
+        Puzzle::ALL.iter().flat_map(|&puzzle| {
+            let series = Series::ALL[SERIES_FOR_EACH_PUZZLE[puzzle.index()]];
+
+            Tier::ALL.iter().flat_map(move |&tier| types_for_tier(tier).map(|i| CardType::ALL[i]).flat_map(move |card_type| {
+                let num_colors = NUM_COLOR_SLOTS_PER_TYPE[card_type.index()];
+                let num_variants = NUM_VARIANTS_PER_TYPE[card_type.index()];
+                let variant_offset = VARIANT_OFFSET_PER_TYPE[card_type.index()];
+
+                let color1_choices = if num_colors < 1 { &[Color::None] } else { &Color::ALL[1..] };
+                let color2_choices = if num_colors < 2 { &[Color::None] } else { &Color::ALL[1..] };
+                let variant_choices = if num_variants < 1 { &[Variant::None] } else { &Variant::ALL[variant_offset..variant_offset + num_variants] };
+
+                color1_choices.iter().flat_map(move |&color1| color2_choices.iter().flat_map(move |&color2| {
+                    variant_choices.iter().flat_map(move |&variant| Condition::ALL.iter().flat_map(move |&condition| {
+                        editions_for(tier, card_type, condition).iter().map(move |&edition| {
+                            PuzzleCard { series, puzzle, tier, card_type, color1, color2, variant, condition, edition }
+                        })
+                    }))
+                }))
+            }))
+        })
+    }
+}
+
+impl CardCounts {
+    pub fn of(cards: impl IntoIterator<Item = PuzzleCard>) -> Self {
+        let mut counts = CardCounts::default();
+
+        for card in cards {
+            counts.total += 1;
+            *counts.per_series.entry(card.series).or_default() += 1;
+            *counts.per_tier.entry(card.tier).or_default() += 1;
+        }
+
+        counts
+    }
+}
+
+impl fmt::Display for CardCounts {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{} card combinations", self.total)?;
+
+        writeln!(f, "\nPer series:")?;
+        for (series, count) in &self.per_series { writeln!(f, "  {:<24}{:>8}", series.name(), count)?; }
+
+        writeln!(f, "\nPer tier:")?;
+        for (tier, count) in &self.per_tier { writeln!(f, "  {:<24}{:>8}", tier.name(), count)?; }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // These match the metadata that ./bin/generate_metadata writes.
+    #[test]
+    fn enumerates_every_card_in_the_metadata_order() {
+        let cards = PuzzleCard::all().collect::<Vec<_>>();
+        let counts = CardCounts::of(cards.iter().copied());
+
+        assert_eq!(counts.total, 1001196);
+        assert_eq!(counts.per_series.values().sum::<usize>(), counts.total);
+        assert_eq!(counts.per_tier[&Tier::Godly], 161240);
+
+        assert_eq!(cards[0].token_id(), 0);
+        assert_eq!(cards.last().unwrap().metadata_id(), format!("{:0>64}", "100306100000160403"));
+    }
+}
@@ -0,0 +1,147 @@
+use std::{collections::BTreeMap, str::FromStr};
+use crate::*;
+
+// Selects cards with an expression such as 'tier=Master,type=Artwork' or
+// 'series="Two by Two"'. Clauses on different fields must all match and
+// repeated clauses on one field match any of their values, e.g. 'type=Cloak,type=Star'.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Filter {
+    clauses: BTreeMap<Field, Vec<usize>>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Field { Series, Puzzle, Tier, Type, Color, Color1, Color2, Variant, Condition, Edition }
+
+const FIELD_NAMES: [(&str, Field); 10] = [
+    ("series", Field::Series),
+    ("puzzle", Field::Puzzle),
+    ("tier", Field::Tier),
+    ("type", Field::Type),
+    ("color", Field::Color), // Matches either color slot.
+    ("color1", Field::Color1),
+    ("color2", Field::Color2),
+    ("variant", Field::Variant),
+    ("condition", Field::Condition),
+    ("edition", Field::Edition),
+];
+
+impl Filter {
+    pub fn matches(&self, card: &PuzzleCard) -> bool {
+        self.clauses.iter().all(|(field, indexes)| match field {
+            Field::Color => indexes.contains(&card.color1.index()) || indexes.contains(&card.color2.index()),
+            _ => indexes.contains(&field.index_of(card)),
+        })
+    }
+}
+
+impl Field {
+    fn from_name(name: &str) -> Option<Self> {
+        FIELD_NAMES.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)).map(|&(_, field)| field)
+    }
+
+    fn index_of(self, card: &PuzzleCard) -> usize {
+        match self {
+            Field::Series => card.series.index(),
+            Field::Puzzle => card.puzzle.index(),
+            Field::Tier => card.tier.index(),
+            Field::Type => card.card_type.index(),
+            Field::Color | Field::Color1 => card.color1.index(),
+            Field::Color2 => card.color2.index(),
+            Field::Variant => card.variant.index(),
+            Field::Condition => card.condition.index(),
+            Field::Edition => card.edition.index(),
+        }
+    }
+
+    fn index_of_name(self, name: &str) -> Option<usize> {
+        match self {
+            Field::Series => Series::from_name(name).map(Series::index),
+            Field::Puzzle => Puzzle::from_name(name).map(Puzzle::index),
+            Field::Tier => Tier::from_name(name).map(Tier::index),
+            Field::Type => CardType::from_name(name).map(CardType::index),
+            Field::Color | Field::Color1 | Field::Color2 => Color::from_name(name).map(Color::index),
+            Field::Variant => Variant::from_name(name).map(Variant::index),
+            Field::Condition => Condition::from_name(name).map(Condition::index),
+            Field::Edition => Edition::from_name(name).map(Edition::index),
+        }
+    }
+}
+
+impl FromStr for Filter {
+    type Err = String;
+
+    fn from_str(expression: &str) -> Result<Self, Self::Err> {
+        let mut filter = Filter::default();
+
+        for clause in split_outside_quotes(expression)? {
+            let (field_name, value) = clause.split_once('=')
+                .ok_or_else(|| format!("Expected field=value but got '{}'", clause))?;
+
+            let field = Field::from_name(field_name.trim()).ok_or_else(|| {
+                let names = FIELD_NAMES.iter().map(|(n, _)| *n).collect::<Vec<_>>();
+                format!("Unknown field '{}'. Expected one of: {}", field_name.trim(), names.join(", "))
+            })?;
+
+            let value = value.trim().trim_matches('"');
+            let index = field.index_of_name(value).ok_or_else(|| format!("'{}' is not a valid {}", value, field_name.trim()))?;
+
+            filter.clauses.entry(field).or_default().push(index);
+        }
+
+        Ok(filter)
+    }
+}
+
+// Names such as "Ahoy, Me Crab" contain commas so they need to be quoted.
+fn split_outside_quotes(expression: &str) -> Result<Vec<&str>, String> {
+    let mut clauses = vec![];
+    let (mut start, mut in_quotes) = (0, false);
+
+    for (i, c) in expression.char_indices() {
+        match c {
+            '"' => in_quotes = !in_quotes,
+            ',' if !in_quotes => { clauses.push(&expression[start..i]); start = i + 1; },
+            _ => {},
+        }
+    }
+
+    if in_quotes { return Err(format!("Unterminated quote in '{}'", expression)); }
+    clauses.push(&expression[start..]);
+
+    Ok(clauses.into_iter().filter(|c| !c.trim().is_empty()).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn card(puzzle: Puzzle, card_type: CardType) -> PuzzleCard {
+        PuzzleCard::all().find(|c| c.puzzle == puzzle && c.card_type == card_type).unwrap()
+    }
+
+    #[test]
+    fn allows_commas_in_quoted_names() {
+        let filter = "puzzle=\"Ahoy, Me Crab\",type=Player".parse::<Filter>().unwrap();
+
+        assert!(filter.matches(&card(Puzzle::AhoyMeCrab, CardType::Player)));
+        assert!(!filter.matches(&card(Puzzle::AhoyMeCrab, CardType::Cloak)));
+        assert!(!filter.matches(&card(Puzzle::CrabIsland, CardType::Player)));
+    }
+
+    #[test]
+    fn matches_any_value_of_a_repeated_field() {
+        let filter = "type=Cloak, type=star".parse::<Filter>().unwrap();
+
+        assert!(filter.matches(&card(Puzzle::WhatIsThis, CardType::Cloak)));
+        assert!(filter.matches(&card(Puzzle::WhatIsThis, CardType::Star)));
+        assert!(!filter.matches(&card(Puzzle::WhatIsThis, CardType::Player)));
+    }
+
+    #[test]
+    fn rejects_invalid_expressions() {
+        assert!("shape=Round".parse::<Filter>().unwrap_err().starts_with("Unknown field 'shape'"));
+        assert!("puzzle=\"Ahoy, Me Crab".parse::<Filter>().unwrap_err().starts_with("Unterminated quote"));
+        assert!("tier".parse::<Filter>().unwrap_err().starts_with("Expected field=value"));
+        assert!("tier=Legendary".parse::<Filter>().unwrap_err().contains("not a valid tier"));
+    }
+}
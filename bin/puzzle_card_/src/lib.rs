@@ -0,0 +1,215 @@
+// This crate decodes PuzzleCard token IDs into named fields so that the Rust
+// tools in ./bin know which card they are working on. It mirrors the token
+// layout and constants of public/PuzzleCard.js.
+
+use std::fmt;
+
+macro_rules! named_enum {
+    ($enum:ident, $names:ident, [$($variant:ident),* $(,)?]) => {
+        #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+        pub enum $enum { $($variant),* }
+
+        impl $enum {
+            pub const ALL: &'static [$enum] = &[$($enum::$variant),*];
+
+            pub fn from_index(index: usize) -> Option<Self> {
+                Self::ALL.get(index).copied()
+            }
+
+            pub fn index(self) -> usize {
+                self as usize
+            }
+
+            pub fn name(self) -> &'static str {
+                $names[self as usize]
+            }
+
+            // Ignores case and allows a plain apostrophe, e.g. "crab's day out".
+            pub fn from_name(name: &str) -> Option<Self> {
+                Self::ALL.iter().copied().find(|v| v.name().replace('’', "'").eq_ignore_ascii_case(&name.replace('’', "'")))
+            }
+        }
+
+        impl std::fmt::Display for $enum {
+            fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str(self.name())
+            }
+        }
+    };
+}
+
+mod constants;
+mod enumeration;
+mod filter;
+mod names;
+mod validation;
+
+pub use constants::*;
+pub use enumeration::*;
+pub use filter::*;
+pub use validation::*;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct PuzzleCard {
+    pub series: Series,
+    pub puzzle: Puzzle,
+    pub tier: Tier,
+    pub card_type: CardType,
+    pub color1: Color,
+    pub color2: Color,
+    pub variant: Variant,
+    pub condition: Condition,
+    pub edition: Edition,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DecodeError {
+    TooManyBytes,
+    InvalidField(&'static str, usize),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MetadataIdError {
+    WrongLength(usize),
+    NotLowercaseHex(char),
+    HighBitsSet,
+}
+
+impl PuzzleCard {
+    // Token IDs are nine bytes, one per field, with the series in the highest byte.
+    pub fn from_token_id(token_id: u128) -> Result<Self, DecodeError> {
+        if token_id >> 72 != 0 { return Err(DecodeError::TooManyBytes); }
+
+        let bytes = token_id.to_be_bytes();
+        let indexes: [usize; 9] = std::array::from_fn(|i| bytes[7 + i] as usize);
+
+        Self::from_indexes(indexes)
+    }
+
+    pub fn from_indexes(indexes: [usize; 9]) -> Result<Self, DecodeError> {
+        let invalid = |field, index| DecodeError::InvalidField(field, index);
+
+        let series = Series::from_index(indexes[0]).ok_or(invalid("Series", indexes[0]))?;
+        let card_type = CardType::from_index(indexes[3]).ok_or(invalid("Type", indexes[3]))?;
+
+        let puzzle_index = PUZZLE_OFFSET_PER_SERIES[series.index()] + indexes[1];
+        let variant_index = VARIANT_OFFSET_PER_TYPE[card_type.index()] + indexes[6];
+
+        Ok(PuzzleCard {
+            series,
+            puzzle: Puzzle::from_index(puzzle_index).ok_or(invalid("Puzzle", indexes[1]))?,
+            tier: Tier::from_index(indexes[2]).ok_or(invalid("Tier", indexes[2]))?,
+            card_type,
+            color1: Color::from_index(indexes[4]).ok_or(invalid("Color1", indexes[4]))?,
+            color2: Color::from_index(indexes[5]).ok_or(invalid("Color2", indexes[5]))?,
+            variant: Variant::from_index(variant_index).ok_or(invalid("Variant", indexes[6]))?,
+            condition: Condition::from_index(indexes[7]).ok_or(invalid("Condition", indexes[7]))?,
+            edition: Edition::from_index(indexes[8]).ok_or(invalid("Edition", indexes[8]))?,
+        })
+    }
+
+    // These can be negative if the puzzle/variant doesn't belong to the series/type.
+    pub fn relative_puzzle_index(&self) -> isize {
+        self.puzzle.index() as isize - PUZZLE_OFFSET_PER_SERIES[self.series.index()] as isize
+    }
+
+    pub fn relative_variant_index(&self) -> isize {
+        self.variant.index() as isize - VARIANT_OFFSET_PER_TYPE[self.card_type.index()] as isize
+    }
+
+    pub fn indexes(&self) -> [isize; 9] {
+        [
+            self.series.index() as isize,
+            self.relative_puzzle_index(),
+            self.tier.index() as isize,
+            self.card_type.index() as isize,
+            self.color1.index() as isize,
+            self.color2.index() as isize,
+            self.relative_variant_index(),
+            self.condition.index() as isize,
+            self.edition.index() as isize,
+        ]
+    }
+
+    pub fn token_id(&self) -> u128 {
+        self.indexes().iter().fold(0, |id, &index| (id << 8) | (index as u8) as u128)
+    }
+
+    pub fn token_hex_string(&self) -> String {
+        let digits = self.indexes().iter().map(|&i| format!("{:02x}", i as u8)).collect::<String>();
+        format!("0x{}", digits)
+    }
+
+    pub fn metadata_id(&self) -> String {
+        format!("{:0>64}", &self.token_hex_string()[2..])
+    }
+
+    // Metadata IDs are 256-bit token IDs as 64 lowercase hex digits, but no card
+    // uses the upper 128 bits so reject them rather than let IDs collide.
+    pub fn token_id_from_metadata_id(metadata_id: &str) -> Result<u128, MetadataIdError> {
+        if metadata_id.len() != 64 { return Err(MetadataIdError::WrongLength(metadata_id.len())); }
+
+        if let Some(c) = metadata_id.chars().find(|c| !matches!(c, '0'..='9' | 'a'..='f')) {
+            return Err(MetadataIdError::NotLowercaseHex(c));
+        }
+
+        let (high_bits, low_bits) = metadata_id.split_at(32);
+        if high_bits.chars().any(|c| c != '0') { return Err(MetadataIdError::HighBitsSet); }
+
+        Ok(u128::from_str_radix(low_bits, 16).unwrap())
+    }
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecodeError::TooManyBytes => write!(f, "Token ID is longer than nine bytes"),
+            DecodeError::InvalidField(field, index) => write!(f, "{} index {} is invalid", field, index),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+impl fmt::Display for MetadataIdError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MetadataIdError::WrongLength(length) => write!(f, "Expected 64 hex digits but there are {}", length),
+            MetadataIdError::NotLowercaseHex(c) => write!(f, "'{}' is not a lowercase hex digit", c),
+            MetadataIdError::HighBitsSet => write!(f, "The upper 128 bits are not zero"),
+        }
+    }
+}
+
+impl std::error::Error for MetadataIdError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_card_round_trips_through_its_ids() {
+        for card in PuzzleCard::all() {
+            let token_id = card.token_id();
+
+            assert_eq!(PuzzleCard::from_token_id(token_id), Ok(card));
+            assert_eq!(PuzzleCard::token_id_from_metadata_id(&card.metadata_id()), Ok(token_id));
+            assert_eq!(card.validate(), Ok(()));
+        }
+    }
+
+    #[test]
+    fn rejects_token_ids_longer_than_nine_bytes() {
+        assert_eq!(PuzzleCard::from_token_id(1 << 72), Err(DecodeError::TooManyBytes));
+    }
+
+    #[test]
+    fn rejects_invalid_metadata_ids() {
+        let valid = format!("{:0>64}", "100306100000160403");
+
+        assert_eq!(PuzzleCard::token_id_from_metadata_id(&valid[1..]), Err(MetadataIdError::WrongLength(63)));
+        assert_eq!(PuzzleCard::token_id_from_metadata_id(&format!("{:0>64}", "10030610000016040A")), Err(MetadataIdError::NotLowercaseHex('A')));
+        assert_eq!(PuzzleCard::token_id_from_metadata_id(&format!("1{}", &valid[1..])), Err(MetadataIdError::HighBitsSet));
+        assert_eq!(PuzzleCard::token_id_from_metadata_id(&valid), Ok(0x100306100000160403));
+    }
+}
@@ -0,0 +1,43 @@
+use std::fmt;
+use crate::*;
+
+impl PuzzleCard {
+    // Follows the wording of openSeaTitle in bin/generate_metadata.js but without the tier.
+    pub fn kind(&self) -> String {
+        let (color1, color2, variant) = (self.color1, self.color2, self.variant);
+
+        let pair_of_colors = if color1 == color2 { color1.to_string() } else { format!("{} and {}", color1, color2) };
+        let with_variant = |name: CardType| if variant == Variant::None { name.to_string() } else { format!("{} ({})", name, variant) };
+
+        match self.card_type {
+            CardType::Cloak | CardType::Beacon | CardType::Star => format!("{} {}", color1, self.card_type),
+            CardType::Inactive | CardType::Active => format!("{} {} {}", self.card_type, color1, variant),
+            CardType::Telescope => format!("{} {} Telescope", color1, variant),
+            CardType::Helix if color1 == color2 => format!("Double {} Helix", color1),
+            CardType::Helix | CardType::Torch | CardType::Glasses => format!("{} {}", pair_of_colors, self.card_type),
+            card_type => with_variant(card_type),
+        }
+    }
+
+    // Renders a token ID as its card name, or explains why it isn't a card.
+    pub fn describe(token_id: u128) -> String {
+        match PuzzleCard::from_token_id(token_id) {
+            Ok(card) => card.to_string(),
+            Err(error) => format!("Unknown card {} ({})", token_id, error),
+        }
+    }
+}
+
+// E.g. "Mortal Red Cloak — Hidden Entrance, Pristine"
+impl fmt::Display for PuzzleCard {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {} — {}, {}", self.tier, self.kind(), self.puzzle, self.condition)?;
+
+        match self.edition {
+            Edition::Standard => Ok(()),
+            Edition::Signed => write!(f, ", Signed"),
+            Edition::Limited => write!(f, ", Limited Edition"),
+            Edition::MasterCopy => write!(f, ", Master Copy"),
+        }
+    }
+}
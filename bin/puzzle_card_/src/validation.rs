@@ -0,0 +1,121 @@
+use std::ops::RangeInclusive;
+use crate::*;
+
+// These mirror the card combination rules in bin/generate_metadata.js.
+
+pub const STANDARD_TYPES: RangeInclusive<usize> = 0..=13;
+pub const VIRTUAL_TYPES: RangeInclusive<usize> = 0..=14;
+pub const MASTER_TYPES: RangeInclusive<usize> = 15..=16;
+
+pub const NON_LIMITED_EDITIONS: [Edition; 2] = [Edition::Standard, Edition::Signed];
+pub const STANDARD_EDITION_ONLY: [Edition; 1] = [Edition::Standard];
+
+pub fn types_for_tier(tier: Tier) -> RangeInclusive<usize> {
+    match tier {
+        Tier::Master => MASTER_TYPES,
+        Tier::Virtual | Tier::Godly => VIRTUAL_TYPES,
+        _ => STANDARD_TYPES,
+    }
+}
+
+pub fn editions_for(tier: Tier, card_type: CardType, condition: Condition) -> &'static [Edition] {
+    let is_master_artwork = tier == Tier::Master && card_type == CardType::Artwork;
+
+    if is_master_artwork && condition == Condition::Pristine {
+        Edition::ALL
+    } else if is_master_artwork {
+        &NON_LIMITED_EDITIONS
+    } else {
+        &STANDARD_EDITION_ONLY
+    }
+}
+
+impl PuzzleCard {
+    // Returns every reason the card could not have been generated by ./bin/generate_metadata.
+    pub fn validate(&self) -> Result<(), Vec<String>> {
+        let mut errors = vec![];
+        let type_index = self.card_type.index();
+
+        let num_puzzles = NUM_PUZZLES_PER_SERIES[self.series.index()] as isize;
+        if !(0..num_puzzles).contains(&self.relative_puzzle_index()) {
+            errors.push(format!("'{}' is not a puzzle in the '{}' series", self.puzzle, self.series));
+        }
+
+        if !types_for_tier(self.tier).contains(&type_index) {
+            errors.push(format!("{} cards don't exist at the {} tier", self.card_type, self.tier));
+        }
+
+        let num_colors = NUM_COLOR_SLOTS_PER_TYPE[type_index];
+
+        for (slot, color) in [(1, self.color1), (2, self.color2)] {
+            if num_colors < slot && color != Color::None {
+                errors.push(format!("{} cards have no color{} but it is {}", self.card_type, slot, color));
+            } else if num_colors >= slot && color == Color::None {
+                errors.push(format!("{} cards need a color{} but it is None", self.card_type, slot));
+            }
+        }
+
+        let num_variants = NUM_VARIANTS_PER_TYPE[type_index] as isize;
+        if num_variants == 0 && self.variant != Variant::None {
+            errors.push(format!("{} cards have no variant but it is '{}'", self.card_type, self.variant));
+        } else if num_variants > 0 && !(0..num_variants).contains(&self.relative_variant_index()) {
+            errors.push(format!("'{}' is not a variant of {} cards", self.variant, self.card_type));
+        }
+
+        if !editions_for(self.tier, self.card_type, self.condition).contains(&self.edition) {
+            errors.push(format!("{} edition doesn't exist for {} {} cards in {} condition", self.edition, self.tier, self.card_type, self.condition));
+        }
+
+        if errors.is_empty() { Ok(()) } else { Err(errors) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PLAYER: PuzzleCard = PuzzleCard {
+        series: Series::TheBeginning,
+        puzzle: Puzzle::WhatIsThis,
+        tier: Tier::Mortal,
+        card_type: CardType::Player,
+        color1: Color::None,
+        color2: Color::None,
+        variant: Variant::IdleFront,
+        condition: Condition::Excellent,
+        edition: Edition::Standard,
+    };
+
+    fn errors(card: PuzzleCard) -> Vec<String> {
+        card.validate().err().unwrap_or_default()
+    }
+
+    #[test]
+    fn rejects_a_puzzle_outside_its_series() {
+        assert_eq!(errors(PuzzleCard { puzzle: Puzzle::InTheDark, ..PLAYER }), ["'In the Dark' is not a puzzle in the 'The Beginning' series"]);
+    }
+
+    #[test]
+    fn rejects_a_type_that_does_not_exist_at_the_tier() {
+        assert_eq!(errors(PuzzleCard { tier: Tier::Master, ..PLAYER }), ["Player cards don't exist at the Master tier"]);
+    }
+
+    #[test]
+    fn rejects_the_wrong_color_slots() {
+        assert_eq!(errors(PuzzleCard { color1: Color::Yellow, ..PLAYER }), ["Player cards have no color1 but it is Yellow"]);
+
+        let cloak = PuzzleCard { card_type: CardType::Cloak, variant: Variant::None, ..PLAYER };
+        assert_eq!(errors(cloak), ["Cloak cards need a color1 but it is None"]);
+    }
+
+    #[test]
+    fn rejects_a_variant_outside_the_type() {
+        assert_eq!(errors(PuzzleCard { variant: Variant::Sun, ..PLAYER }), ["'Sun' is not a variant of Player cards"]);
+    }
+
+    #[test]
+    fn rejects_editions_other_than_standard_except_for_master_artwork() {
+        assert_eq!(errors(PLAYER), Vec::<String>::new());
+        assert_eq!(errors(PuzzleCard { edition: Edition::Signed, ..PLAYER }), ["Signed edition doesn't exist for Mortal Player cards in Excellent condition"]);
+    }
+}
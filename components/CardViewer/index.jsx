@@ -2,12 +2,14 @@ import { useEffect, useState, useRef } from "react";
 import Flippable from "../Flippable";
 import CardFront from "../CardFront";
 import CardBack from "../CardBack";
+import whenFullyRendered from "./readiness";
 import styles from "./styles.module.scss";
 
 const CardViewer = ({ card, referrer }) => {
   const [flipped, setFlipped] = useState(false);
   const [flipDirection, setFlipDirection] = useState(1);
   const [fullscreen, setFullscreen] = useState(false);
+  const [readyToCapture, setReadyToCapture] = useState(false);
 
   const ref = useRef();
 
@@ -48,15 +50,24 @@ const CardViewer = ({ card, referrer }) => {
     setFlipDirection(direction);
   };
 
+  // Let ./bin/generate_images know when it can take the screenshot.
+  useEffect(() => {
+    if (referrer !== "generate_images") { return; }
+    let cancelled = false;
+
+    whenFullyRendered(ref.current).then(() => !cancelled && setReadyToCapture(true));
+    return () => { cancelled = true; };
+  }, [referrer]);
+
   // Present CardViewer differently depending on how the user arrived at this page.
   // e.g don't show the felt background when taking screenshots in ./bin/generate_images
   const referrerClass = `referrer_${referrer}`;
 
   return (
-    <div className={`${styles.card_viewer} ${referrerClass}`} ref={ref}>
+    <div className={`${styles.card_viewer} ${referrerClass}`} ref={ref} data-ready-to-capture={readyToCapture || undefined}>
       <button onClick={() => flipCard(-1)} className={`${styles.tick_mark} ${styles.flip_left}`}></button>
 
-      <div className={styles.card} onClick={() => toggleFullscreen()}>
+      <div className={styles.card} onClick={() => toggleFullscreen()} data-capture-target>
         <Flippable flipped={flipped} direction={flipDirection} className={styles.flippable}>
           <CardFront card={card} random={random} defects={defects} scaleShadows={true} videoQuality="high" />
           <CardBack defects={defects} scaleShadows={true} isMasterCopy={card.edition === "Master Copy"} />
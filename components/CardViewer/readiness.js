@@ -0,0 +1,52 @@
+// Resolves once everything in the element has rendered so that ./bin/generate_images
+// doesn't capture the card with missing fonts, images, textures or a blank video.
+const whenFullyRendered = async (element) => {
+  await document.fonts.ready;
+
+  const images = [...element.querySelectorAll("img")];
+  await Promise.all(images.map(image => image.decode().catch(() => {})));
+
+  // The card's textures, e.g. the paper and foil, are CSS background images.
+  await Promise.all(backgroundImageUrls(element).map(whenImageDecoded));
+
+  const videos = [...element.querySelectorAll("video")];
+  await Promise.all(videos.map(whenVideoHasFrame));
+
+  // Wait for the decoded images and video frames to be painted.
+  await nextFrame();
+  await nextFrame();
+};
+
+const backgroundImageUrls = (element) => {
+  const elements = [element, ...element.querySelectorAll("*")];
+  const styles = elements.flatMap(e => [getComputedStyle(e), getComputedStyle(e, "::before"), getComputedStyle(e, "::after")]);
+
+  const urls = styles.flatMap(style => [...style.backgroundImage.matchAll(/url\(["']?(.*?)["']?\)/g)].map(match => match[1]));
+  return [...new Set(urls)];
+};
+
+// Resolves even if it fails to load so a missing texture can't stall the capture.
+const whenImageDecoded = (src) => {
+  const image = new Image();
+  image.src = src;
+
+  return image.decode().catch(() => {});
+};
+
+const whenVideoHasFrame = (video) => new Promise(resolve => {
+  const events = ["loadeddata", "canplay", "seeked"];
+
+  const check = () => {
+    if (video.readyState < 2 || video.seeking) { return; } // HAVE_CURRENT_DATA
+
+    events.forEach(e => video.removeEventListener(e, check));
+    resolve();
+  };
+
+  events.forEach(e => video.addEventListener(e, check));
+  check();
+});
+
+const nextFrame = () => new Promise(resolve => requestAnimationFrame(resolve));
+
+export default whenFullyRendered;